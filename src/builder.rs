@@ -0,0 +1,591 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::ext2::SECTOR_SIZE;
+use crate::ext2::{MIN_BLOCK_SIZE, MAX_BLOCK_SIZE};
+use crate::ext2::superblock::Superblock;
+use crate::ext2::superblock::SUPERBLOCK_START;
+use crate::ext2::inode::Inode;
+use crate::ext2::inode::EXT2_GOOD_OLD_INODE_SIZE;
+use crate::ext2::bgd::BGD;
+use crate::ext2::directory::Directory;
+use crate::ext2::features::{Feature, Features};
+use crate::image::Ext2Image;
+use crate::image::ROOT_INO;
+use crate::populate::populate;
+use crate::error::{Ext2Error, Result};
+
+// Where the reserved sectors go on the device
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReservedPlacement {
+    // Right after group 0's inode table
+    AfterMetadata,
+    // Starting at a fixed sector, rounded to blocks by the Rounding policy
+    Sector(u64),
+    // At the tail of the device, outside the filesystem
+    End
+}
+
+// How reserved sectors that don't line up with blocks become blocks
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rounding {
+    // Protect every block with a reserved sector in it
+    Up,
+    // Only protect blocks that are reserved completely
+    Down,
+    // Refuse reservations that aren't block aligned
+    Reject
+}
+
+impl Rounding {
+    // Turns a range of sectors into a range of blocks, as (first, end)
+    fn blocks(self, start : u64, sectors : u64, sectors_per_block : u64) -> Result<(u64, u64)> {
+	// An empty reservation doesn't need any blocks, wherever it is
+	if sectors == 0 {
+	    return Ok((start / sectors_per_block, start / sectors_per_block));
+	}
+	let end = start + sectors;
+	match self {
+	    Rounding::Up => Ok((start / sectors_per_block, end.div_ceil(sectors_per_block))),
+	    Rounding::Down => {
+		let first = start.div_ceil(sectors_per_block);
+		Ok((first, std::cmp::max(first, end / sectors_per_block)))
+	    },
+	    Rounding::Reject => {
+		if !start.is_multiple_of(sectors_per_block) || !end.is_multiple_of(sectors_per_block) {
+		    return Err(Ext2Error::InvalidGeometry(format!("Reserved sectors {} to {} are not on {} sector block boundaries", start, end - 1, sectors_per_block)));
+		}
+		Ok((start / sectors_per_block, end / sectors_per_block))
+	    }
+	}
+    }
+
+    // Number of blocks for a reservation that has no fixed place
+    fn block_count(self, sectors : u64, sectors_per_block : u64) -> Result<u64> {
+	match self {
+	    Rounding::Up => Ok(sectors.div_ceil(sectors_per_block)),
+	    Rounding::Down => Ok(sectors / sectors_per_block),
+	    Rounding::Reject if sectors.is_multiple_of(sectors_per_block) => Ok(sectors / sectors_per_block),
+	    Rounding::Reject => Err(Ext2Error::InvalidGeometry(format!("{} reserved sectors is not a whole number of {} sector blocks", sectors, sectors_per_block)))
+	}
+    }
+}
+
+// Order of group 0's metadata and the reserved blocks placed after it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Layout {
+    // Bitmaps and inode table, then the reserved blocks
+    MetadataFirst,
+    // Reserved blocks right after the descriptors, then the bitmaps and
+    // inode table, so the reservation doesn't limit the inode table
+    ReservedFirst
+}
+
+#[derive(Debug, Clone)]
+pub struct Ext2Builder {
+    // Sizes and offsets are in sectors of this many bytes
+    sector_size : u32,
+    num_sectors : u64,
+    reserved_sectors : u64,
+    reserved_placement : ReservedPlacement,
+    layout : Layout,
+    rounding : Rounding,
+    // Further reserved regions at fixed places, as (first sector, sectors)
+    reserved_regions : Vec<(u64, u64)>,
+    // Written into the reserved sectors, optionally padded out to fill them
+    reserved_payload : Option<PathBuf>,
+    payload_padding : Option<u8>,
+    // Written to the start of the device, before the superblock
+    boot_code : Option<PathBuf>,
+    check_boot_signature : bool,
+    features : Features,
+    block_size : u32,
+    inode_size : Option<u16>,
+    blocks_per_group : Option<u32>,
+    inodes_per_group : Option<u32>,
+    inode_ratio : Option<u32>,
+    inodes_count : Option<u32>,
+    root : Option<PathBuf>
+}
+
+const MIN_BLOCKS_PER_GROUP : u32 = 256;
+const MIN_INODE_RATIO : u32 = 1024;
+
+impl Ext2Builder {
+    pub fn new(num_sectors : u64) -> Self {
+	Ext2Builder {
+	    sector_size: SECTOR_SIZE,
+	    num_sectors,
+	    reserved_sectors: 0,
+	    reserved_placement: ReservedPlacement::AfterMetadata,
+	    layout: Layout::MetadataFirst,
+	    rounding: Rounding::Up,
+	    reserved_regions: Vec::new(),
+	    reserved_payload: None,
+	    payload_padding: None,
+	    boot_code: None,
+	    check_boot_signature: false,
+	    features: Features::default(),
+	    block_size: MIN_BLOCK_SIZE,
+	    inode_size: None,
+	    blocks_per_group: None,
+	    inodes_per_group: None,
+	    inode_ratio: None,
+	    inodes_count: None,
+	    root: None
+	}
+    }
+
+    pub fn sector_size(mut self, sector_size : u32) -> Self {
+	self.sector_size = sector_size;
+	self
+    }
+
+    pub fn num_sectors(mut self, num_sectors : u64) -> Self {
+	self.num_sectors = num_sectors;
+	self
+    }
+
+    pub fn reserved_sectors(mut self, reserved_sectors : u64) -> Self {
+	self.reserved_sectors = reserved_sectors;
+	self
+    }
+
+    pub fn reserved_placement(mut self, placement : ReservedPlacement) -> Self {
+	self.reserved_placement = placement;
+	self
+    }
+
+    // Only matters for reservations placed after the metadata
+    pub fn layout(mut self, layout : Layout) -> Self {
+	self.layout = layout;
+	self
+    }
+
+    pub fn rounding(mut self, rounding : Rounding) -> Self {
+	self.rounding = rounding;
+	self
+    }
+
+    pub fn reserved_region(mut self, start : u64, sectors : u64) -> Self {
+	self.reserved_regions.push((start, sectors));
+	self
+    }
+
+    pub fn reserved_payload(mut self, payload : &Path) -> Self {
+	self.reserved_payload = Some(payload.to_path_buf());
+	self
+    }
+
+    pub fn payload_padding(mut self, padding : u8) -> Self {
+	self.payload_padding = Some(padding);
+	self
+    }
+
+    pub fn boot_code(mut self, boot_code : &Path) -> Self {
+	self.boot_code = Some(boot_code.to_path_buf());
+	self
+    }
+
+    // Requires the boot code to end its first sector with the MBR signature
+    pub fn check_boot_signature(mut self, check : bool) -> Self {
+	self.check_boot_signature = check;
+	self
+    }
+
+    pub fn features(mut self, features : Features) -> Self {
+	self.features = features;
+	self
+    }
+
+    pub fn block_size(mut self, block_size : u32) -> Self {
+	self.block_size = block_size;
+	self
+    }
+
+    // Setting an inode size makes a revision 1 filesystem, even at the old
+    // 128 bytes. Bigger inodes get nanosecond and creation times.
+    pub fn inode_size(mut self, inode_size : u16) -> Self {
+	self.inode_size = Some(inode_size);
+	self
+    }
+
+    pub fn blocks_per_group(mut self, blocks_per_group : u32) -> Self {
+	self.blocks_per_group = Some(blocks_per_group);
+	self
+    }
+
+    // The inode count can be given per group, as a total, or as a number of
+    // bytes per inode, in that order of precedence.
+    pub fn inodes_per_group(mut self, inodes_per_group : u32) -> Self {
+	self.inodes_per_group = Some(inodes_per_group);
+	self
+    }
+
+    pub fn inodes_count(mut self, inodes_count : u32) -> Self {
+	self.inodes_count = Some(inodes_count);
+	self
+    }
+
+    pub fn inode_ratio(mut self, bytes_per_inode : u32) -> Self {
+	self.inode_ratio = Some(bytes_per_inode);
+	self
+    }
+
+    // Host directory whose contents are copied into the root directory
+    pub fn root(mut self, root : &Path) -> Self {
+	self.root = Some(root.to_path_buf());
+	self
+    }
+
+    fn geometry(&self, sb : &mut Superblock) -> Result<()> {
+	let block_size = sb.block_size();
+	if let Some(bpg) = self.blocks_per_group {
+	    if bpg % 8 != 0 || bpg < MIN_BLOCKS_PER_GROUP || bpg > block_size * 8 {
+		return Err(Ext2Error::InvalidGeometry(format!("Invalid number of blocks per group: {}", bpg)));
+	    }
+	    sb.set_blocks_per_group(bpg)?;
+	}
+
+	let ipg = if let Some(ipg) = self.inodes_per_group {
+	    ipg
+	} else if let Some(count) = self.inodes_count {
+	    count.div_ceil(sb.num_groups())
+	} else if let Some(ratio) = self.inode_ratio {
+	    if ratio < MIN_INODE_RATIO {
+		return Err(Ext2Error::InvalidGeometry(format!("Invalid inode ratio: {}", ratio)));
+	    }
+	    (sb.s_blocks_per_group as u64 * block_size as u64).div_ceil(ratio as u64) as u32
+	} else {
+	    sb.s_inodes_per_group
+	};
+	if ipg == 0 || ipg > block_size * 8 || ipg > u16::MAX as u32 {
+	    return Err(Ext2Error::InvalidGeometry(format!("Invalid number of inodes per group: {}", ipg)));
+	}
+	sb.set_inodes_per_group(ipg)?;
+
+	// Every group carries a superblock, descriptors, bitmaps and inode table
+	let bgd = BGD::new(sb, 0)?;
+	let overhead = bgd.bg_inode_table + sb.itable_blocks() - bgd.get_start(sb);
+	if overhead >= sb.s_blocks_per_group {
+	    // Group 0 always has a copy of the descriptors, and with small
+	    // groups on a big device they can fill it up on their own
+	    let descriptors = bgd.descriptors_end(sb) - bgd.get_start(sb);
+	    if descriptors + 3 >= sb.s_blocks_per_group {
+		return Err(Ext2Error::InvalidGeometry(format!("Descriptors for {} groups take {} blocks, which doesn't fit in {} blocks per group; use larger groups or a larger block size",
+							      sb.num_groups(), sb.gdt_blocks(), sb.s_blocks_per_group)));
+	    }
+	    return Err(Ext2Error::InvalidGeometry(format!("{} inodes per group don't fit in {} blocks", sb.s_inodes_per_group, sb.s_blocks_per_group)));
+	}
+	// Drop a trailing group that is too small to hold its own metadata
+	let last = (sb.s_blocks_count - sb.s_first_data_block) % sb.s_blocks_per_group;
+	if last != 0 && last <= overhead {
+	    sb.set_blocks_count(sb.s_blocks_count - last)?;
+	}
+	if sb.num_groups() == 0 {
+	    return Err(Ext2Error::InvalidGeometry(String::from("Image is too small")));
+	}
+	Ok(())
+    }
+
+    // All the reserved regions with a fixed place on the device, sorted
+    fn fixed_regions(&self) -> Vec<(u64, u64)> {
+	let mut regions = self.reserved_regions.clone();
+	match self.reserved_placement {
+	    ReservedPlacement::Sector(sector) => regions.push((sector, self.reserved_sectors)),
+	    ReservedPlacement::End => regions.push((self.num_sectors - self.reserved_sectors, self.reserved_sectors)),
+	    ReservedPlacement::AfterMetadata => ()
+	}
+	regions.retain(|&(_, sectors)| sectors > 0);
+	regions.sort();
+	regions
+    }
+
+    // Marks a fixed range of sectors as used, rounded to whole blocks.
+    // Returns the sectors that ended up protected, as (first, sectors).
+    fn reserve_region(&self, image : &mut Ext2Image, start : u64, sectors : u64) -> Result<(u64, u64)> {
+	let sectors_per_block = (image.sb.block_size() / self.sector_size) as u64;
+	if start > self.num_sectors || sectors > self.num_sectors - start {
+	    return Err(Ext2Error::TooManyReservedSectors {
+		reserved: sectors,
+		available: self.num_sectors.saturating_sub(start)
+	    });
+	}
+	let (first, end) = self.rounding.blocks(start, sectors, sectors_per_block)?;
+	image.reserve_at(first, end - first)?;
+	let first = first * sectors_per_block;
+	Ok((first, std::cmp::min(end * sectors_per_block, self.num_sectors).saturating_sub(first)))
+    }
+
+    // Spreads the payload over the given sector extents in order
+    fn write_payload(&self, image : &mut Ext2Image, path : &Path, extents : &[(u64, u64)]) -> Result<()> {
+	let mut payload = fs::read(path).map_err(|e| Ext2Error::io(format!("reading {}", path.display()), e))?;
+	let capacity = extents.iter().map(|&(_, sectors)| sectors).sum::<u64>() * self.sector_size as u64;
+	if payload.len() as u64 > capacity {
+	    return Err(Ext2Error::OutOfSpace(format!("{} is {} bytes, but only {} bytes are reserved", path.display(), payload.len(), capacity)));
+	}
+	if let Some(padding) = self.payload_padding {
+	    payload.resize(capacity as usize, padding);
+	}
+	let mut rest = &payload[..];
+	for &(start, sectors) in extents {
+	    if rest.is_empty() {
+		break;
+	    }
+	    let len = std::cmp::min(rest.len() as u64, sectors * self.sector_size as u64) as usize;
+	    image.raw.insert(start * self.sector_size as u64, rest[.. len].to_vec());
+	    image.payload.push((start, (len as u64).div_ceil(self.sector_size as u64)));
+	    rest = &rest[len ..];
+	}
+	Ok(())
+    }
+
+    fn write_boot_code(&self, image : &mut Ext2Image, path : &Path) -> Result<()> {
+	let code = fs::read(path).map_err(|e| Ext2Error::io(format!("reading {}", path.display()), e))?;
+	if code.len() as u64 > SUPERBLOCK_START {
+	    return Err(Ext2Error::InvalidInput(format!("{} bytes of boot code would overlap the superblock", code.len())));
+	}
+	if code.len() != SECTOR_SIZE as usize && code.len() != 2 * SECTOR_SIZE as usize {
+	    return Err(Ext2Error::InvalidInput(format!("Boot code has to be 512 or 1024 bytes, not {}", code.len())));
+	}
+	if self.check_boot_signature && code[510 .. 512] != [0x55, 0xaa] {
+	    return Err(Ext2Error::InvalidInput(format!("{} has no boot signature", path.display())));
+	}
+	if let Some(&offset) = image.raw.keys().next() {
+	    if offset < code.len() as u64 {
+		return Err(Ext2Error::InvalidInput(String::from("Boot code overlaps the reserved payload")));
+	    }
+	}
+	image.raw.insert(0, code);
+	Ok(())
+    }
+
+    pub fn build(&self) -> Result<Ext2Image> {
+	let block_size = self.block_size;
+	if !block_size.is_power_of_two() || !(MIN_BLOCK_SIZE ..= MAX_BLOCK_SIZE).contains(&block_size) {
+	    return Err(Ext2Error::InvalidGeometry(format!("Unsupported block size {}", block_size)));
+	}
+	// A block has to be a whole number of sectors
+	let sector_size = self.sector_size;
+	if !sector_size.is_power_of_two() || sector_size < SECTOR_SIZE || sector_size > block_size {
+	    return Err(Ext2Error::InvalidGeometry(format!("Unsupported sector size {} for {} byte blocks", sector_size, block_size)));
+	}
+	self.features.validate()?;
+	if let Some(inode_size) = self.inode_size {
+	    if !inode_size.is_power_of_two() || (inode_size as u32) < EXT2_GOOD_OLD_INODE_SIZE || inode_size as u32 > block_size {
+		return Err(Ext2Error::InvalidGeometry(format!("Unsupported inode size {} for {} byte blocks", inode_size, block_size)));
+	    }
+	}
+	if self.num_sectors.checked_mul(sector_size as u64).is_none() {
+	    return Err(Ext2Error::Overflow(format!("{} sectors is too large", self.num_sectors)));
+	}
+	if self.reserved_sectors > self.num_sectors {
+	    return Err(Ext2Error::TooManyReservedSectors {
+		reserved: self.reserved_sectors,
+		available: self.num_sectors
+	    });
+	}
+	let regions = self.fixed_regions();
+	for &(start, sectors) in &regions {
+	    if start.checked_add(sectors).is_none_or(|end| end > self.num_sectors) {
+		return Err(Ext2Error::TooManyReservedSectors {
+		    reserved: sectors,
+		    available: self.num_sectors.saturating_sub(start)
+		});
+	    }
+	}
+	for pair in regions.windows(2) {
+	    if pair[0].0 + pair[0].1 > pair[1].0 {
+		return Err(Ext2Error::InvalidGeometry(format!("Reserved regions at sectors {} and {} overlap", pair[0].0, pair[1].0)));
+	    }
+	}
+	// Reserving the tail just leaves it out of the filesystem
+	let sectors_per_block = (block_size / sector_size) as u64;
+	let region_sectors : u64 = self.reserved_regions.iter().map(|&(_, sectors)| sectors).sum();
+	let (fs_sectors, fs_reserved_sectors) = match self.reserved_placement {
+	    ReservedPlacement::End if self.reserved_sectors > 0 => {
+		let (first, _) = self.rounding.blocks(self.num_sectors - self.reserved_sectors, self.reserved_sectors, sectors_per_block)?;
+		(std::cmp::min(first * sectors_per_block, self.num_sectors), region_sectors)
+	    },
+	    ReservedPlacement::End => (self.num_sectors, region_sectors),
+	    _ => (self.num_sectors, self.reserved_sectors + region_sectors)
+	};
+	if fs_sectors * sector_size as u64 <= 2 * block_size as u64 {
+	    return Err(Ext2Error::InvalidGeometry(String::from("Image is too small")));
+	}
+	let mut sb = Superblock::new(fs_sectors * sector_size as u64, fs_reserved_sectors.saturating_mul(sector_size as u64), block_size)?;
+	sb.set_features(self.features);
+	if let Some(inode_size) = self.inode_size {
+	    sb.set_inode_size(inode_size)?;
+	}
+	self.geometry(&mut sb)?;
+	let mut image = Ext2Image::new(sb)?;
+	let itable_blocks = image.sb.itable_blocks();
+	let reserved_blocks = self.rounding.block_count(self.reserved_sectors, sectors_per_block)?;
+	let reserved_first = self.layout == Layout::ReservedFirst && self.reserved_placement == ReservedPlacement::AfterMetadata;
+	if reserved_first {
+	    // Group 0's bitmaps and inode table go after the reserved blocks
+	    let first = image.bgds[0].descriptors_end(&image.sb) as u64 + reserved_blocks;
+	    let group_end = image.bgds[0].get_start(&image.sb) + image.bgds[0].num_blocks(&image.sb);
+	    if first + 2 + itable_blocks as u64 > group_end as u64 {
+		let available = group_end.saturating_sub(image.bgds[0].descriptors_end(&image.sb) + 2 + itable_blocks);
+		return Err(Ext2Error::TooManyReservedSectors {
+		    reserved: self.reserved_sectors,
+		    available: available as u64 * sectors_per_block
+		});
+	    }
+	    image.bgds[0] = BGD::new_at(&image.sb, 0, first as u32)?;
+	}
+
+	// Each group starts with the superblock and descriptors, and has its
+	// bitmaps and inode table somewhere after them
+	for i in 0 .. image.bgds.len() {
+	    let bgd = &image.bgds[i];
+	    let mut metadata : Vec<u32> = (bgd.get_start(&image.sb) .. bgd.descriptors_end(&image.sb)).collect();
+	    metadata.push(bgd.bg_block_bitmap);
+	    metadata.push(bgd.bg_inode_bitmap);
+	    metadata.extend(bgd.bg_inode_table .. bgd.bg_inode_table + itable_blocks);
+	    for block in metadata {
+		image.set_block_used(block);
+	    }
+	}
+
+	// Fixed regions go first, so the ones placed after the metadata step
+	// over them
+	for &(start, sectors) in &self.reserved_regions {
+	    if sectors > 0 {
+		let protected = self.reserve_region(&mut image, start, sectors)?;
+		image.protected.push(protected);
+	    }
+	}
+	// The sectors of the main reservation, if it has a fixed place
+	let requested = match self.reserved_placement {
+	    ReservedPlacement::Sector(sector) => Some((sector, self.reserved_sectors)),
+	    ReservedPlacement::End => Some((self.num_sectors - self.reserved_sectors, self.reserved_sectors)),
+	    ReservedPlacement::AfterMetadata => None
+	};
+	// The sectors the payload goes in
+	let reserved_extents = match requested {
+	    Some((start, sectors)) => {
+		let (first, protected) = self.reserve_region(&mut image, start, sectors)?;
+		image.protected.push((first, protected));
+		// Rounding up protects more than was asked for, but the
+		// payload still starts at the requested sector
+		let from = std::cmp::max(first, start);
+		let to = std::cmp::min(first + protected, start + sectors);
+		vec![(from, to.saturating_sub(from))]
+	    },
+	    None => {
+		// Right after group 0's inode table, or its descriptors if
+		// the reserved blocks go first, carrying on past the
+		// metadata of any later groups the blocks run into
+		let used_blk_id = if reserved_first {
+		    image.bgds[0].descriptors_end(&image.sb)
+		} else {
+		    image.bgds[0].bg_inode_table + itable_blocks
+		};
+		let available = image.free_blocks_from(used_blk_id);
+		if reserved_blocks > available as u64 {
+		    return Err(Ext2Error::TooManyReservedSectors {
+			reserved: self.reserved_sectors,
+			available: available as u64 * sectors_per_block
+		    });
+		}
+		let extents : Vec<(u64, u64)> = image.reserve(used_blk_id, reserved_blocks as u32)?.iter().map(|&(block, len)| {
+		    (block as u64 * sectors_per_block, len as u64 * sectors_per_block)
+		}).collect();
+		image.protected.extend_from_slice(&extents);
+		// As above, the payload only gets the sectors that were
+		// asked for, not the whole of the last block
+		let mut left = self.reserved_sectors;
+		extents.iter().map(|&(start, sectors)| {
+		    let sectors = std::cmp::min(sectors, left);
+		    left -= sectors;
+		    (start, sectors)
+		}).filter(|&(_, sectors)| sectors > 0).collect()
+	    }
+	};
+	image.protected.retain(|&(_, sectors)| sectors > 0);
+	image.protected.sort();
+	// Regions that round out to the same block protect the same sectors
+	image.protected.dedup_by(|next, prev| {
+	    if next.0 < prev.0 + prev.1 {
+		prev.1 = std::cmp::max(prev.0 + prev.1, next.0 + next.1) - prev.0;
+		true
+	    } else {
+		false
+	    }
+	});
+	if let Some(payload) = &self.reserved_payload {
+	    self.write_payload(&mut image, payload, &reserved_extents)?;
+	}
+	if let Some(boot_code) = &self.boot_code {
+	    self.write_boot_code(&mut image, boot_code)?;
+	}
+
+	for ino in 1 .. image.sb.s_first_ino {
+	    image.set_inode_used(ino);
+	}
+
+	let mut root_inode = Inode::new(true);
+	root_inode.i_block[0] = match image.alloc_block() {
+	    Some(block) => block,
+	    None => return Err(Ext2Error::OutOfSpace(String::from("No space for the root directory")))
+	};
+	root_inode.i_blocks += block_size / SECTOR_SIZE;
+	let root_dir = Directory::new(ROOT_INO, ROOT_INO, block_size, self.features.contains(Feature::Filetype));
+	root_inode.i_size = root_dir.len();
+
+	// The boot loader inode owns the reserved blocks, so e2fsck doesn't
+	// free them
+	let mut boot_inode = Inode::new(false);
+	image.map_reserved(&mut boot_inode)?;
+
+	image.inodes.insert(1, Default::default()); // Bad Blocks inode
+	image.inodes.insert(ROOT_INO, root_inode); // Root Directory inode
+	image.inodes.insert(3, Default::default()); // ACL index inode
+	image.inodes.insert(4, Default::default()); // ACL data inode
+	image.inodes.insert(5, boot_inode); // boot loader inode
+	image.inodes.insert(6, Default::default()); // undelete directory inode
+	image.dirs.insert(ROOT_INO, root_dir);
+	image.bgds[0].bg_used_dirs_count += 1;
+
+	if let Some(root) = &self.root {
+	    populate(&mut image, root)?;
+	}
+
+	Ok(image)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rounding_blocks() {
+	// Sectors 3 to 12 with four sectors per block
+	assert_eq!(Rounding::Up.blocks(3, 10, 4).unwrap(), (0, 4));
+	assert_eq!(Rounding::Down.blocks(3, 10, 4).unwrap(), (1, 3));
+	assert!(matches!(Rounding::Reject.blocks(3, 10, 4), Err(Ext2Error::InvalidGeometry(_))));
+
+	// Nothing but a partial block
+	assert_eq!(Rounding::Up.blocks(5, 2, 4).unwrap(), (1, 2));
+	assert_eq!(Rounding::Down.blocks(5, 2, 4).unwrap(), (2, 2));
+
+	// Aligned ranges come out the same whatever the policy, and empty
+	// ones are empty
+	for rounding in [Rounding::Up, Rounding::Down, Rounding::Reject] {
+	    assert_eq!(rounding.blocks(8, 8, 4).unwrap(), (2, 4));
+	    assert_eq!(rounding.blocks(5, 0, 4).unwrap(), (1, 1));
+	}
+    }
+
+    #[test]
+    fn rounding_block_count() {
+	assert_eq!(Rounding::Up.block_count(9, 4).unwrap(), 3);
+	assert_eq!(Rounding::Down.block_count(9, 4).unwrap(), 2);
+	assert!(Rounding::Reject.block_count(9, 4).is_err());
+	assert_eq!(Rounding::Reject.block_count(8, 4).unwrap(), 2);
+    }
+}
@@ -0,0 +1,100 @@
+use std::io::prelude::*;
+use std::io::{Error, ErrorKind, SeekFrom};
+
+// Anything an image can be written to or read from. Offsets are in bytes
+// from the start of the device; block helpers use the buffer length as the
+// block size.
+pub trait BlockDevice {
+    fn read_at(&mut self, offset : u64, buf : &mut [u8]) -> std::io::Result<()>;
+    fn write_at(&mut self, offset : u64, buf : &[u8]) -> std::io::Result<()>;
+
+    fn read_block(&mut self, block : u64, buf : &mut [u8]) -> std::io::Result<()> {
+	self.read_at(block * buf.len() as u64, buf)
+    }
+
+    fn write_block(&mut self, block : u64, buf : &[u8]) -> std::io::Result<()> {
+	self.write_at(block * buf.len() as u64, buf)
+    }
+}
+
+// A file, or any other seekable stream.
+#[derive(Debug)]
+pub struct FileDevice<T : Read + Write + Seek> {
+    inner : T
+}
+
+impl<T : Read + Write + Seek> FileDevice<T> {
+    pub fn new(inner : T) -> Self {
+	FileDevice {
+	    inner
+	}
+    }
+
+    pub fn into_inner(self) -> T {
+	self.inner
+    }
+}
+
+impl<T : Read + Write + Seek> BlockDevice for FileDevice<T> {
+    fn read_at(&mut self, offset : u64, buf : &mut [u8]) -> std::io::Result<()> {
+	self.inner.seek(SeekFrom::Start(offset))?;
+	self.inner.read_exact(buf)
+    }
+
+    fn write_at(&mut self, offset : u64, buf : &[u8]) -> std::io::Result<()> {
+	self.inner.seek(SeekFrom::Start(offset))?;
+	self.inner.write_all(buf)
+    }
+}
+
+// An in-memory image. Writes past the end grow the buffer, zero filling any gap.
+impl BlockDevice for Vec<u8> {
+    fn read_at(&mut self, offset : u64, buf : &mut [u8]) -> std::io::Result<()> {
+	let start = offset as usize;
+	if start + buf.len() > self.len() {
+	    return Err(Error::new(ErrorKind::UnexpectedEof, "read past the end of the buffer"));
+	}
+	buf.copy_from_slice(&self[start .. start + buf.len()]);
+	Ok(())
+    }
+
+    fn write_at(&mut self, offset : u64, buf : &[u8]) -> std::io::Result<()> {
+	let start = offset as usize;
+	if start + buf.len() > self.len() {
+	    self.resize(start + buf.len(), 0);
+	}
+	self[start .. start + buf.len()].copy_from_slice(buf);
+	Ok(())
+    }
+}
+
+// A device that starts at a byte offset into another one, e.g. a partition
+// inside a larger disk image.
+#[derive(Debug)]
+pub struct OffsetDevice<D : BlockDevice> {
+    inner : D,
+    offset : u64
+}
+
+impl<D : BlockDevice> OffsetDevice<D> {
+    pub fn new(inner : D, offset : u64) -> Self {
+	OffsetDevice {
+	    inner,
+	    offset
+	}
+    }
+
+    pub fn into_inner(self) -> D {
+	self.inner
+    }
+}
+
+impl<D : BlockDevice> BlockDevice for OffsetDevice<D> {
+    fn read_at(&mut self, offset : u64, buf : &mut [u8]) -> std::io::Result<()> {
+	self.inner.read_at(self.offset + offset, buf)
+    }
+
+    fn write_at(&mut self, offset : u64, buf : &[u8]) -> std::io::Result<()> {
+	self.inner.write_at(self.offset + offset, buf)
+    }
+}
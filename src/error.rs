@@ -0,0 +1,53 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+#[derive(Debug)]
+pub enum Ext2Error {
+    // The requested sizes don't make a usable filesystem
+    InvalidGeometry(String),
+    TooManyReservedSectors { reserved : u64, available : u64 },
+    // A value doesn't fit in its on-disk field
+    Overflow(String),
+    OutOfSpace(String),
+    // Bad names or arguments to the image operations
+    InvalidInput(String),
+    // On-disk structures that don't parse
+    Corrupt(String),
+    Io { context : String, source : io::Error }
+}
+
+pub type Result<T> = std::result::Result<T, Ext2Error>;
+
+impl Ext2Error {
+    pub fn io(context : impl Into<String>, source : io::Error) -> Self {
+	Ext2Error::Io {
+	    context: context.into(),
+	    source
+	}
+    }
+}
+
+impl fmt::Display for Ext2Error {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+	match self {
+	    Ext2Error::InvalidGeometry(msg) => write!(f, "Invalid geometry: {}", msg),
+	    Ext2Error::TooManyReservedSectors { reserved, available } =>
+		write!(f, "Too many reserved sectors: {} requested, {} available", reserved, available),
+	    Ext2Error::Overflow(msg) => write!(f, "Overflow: {}", msg),
+	    Ext2Error::OutOfSpace(msg) => write!(f, "Out of space: {}", msg),
+	    Ext2Error::InvalidInput(msg) => write!(f, "{}", msg),
+	    Ext2Error::Corrupt(msg) => write!(f, "Corrupt filesystem: {}", msg),
+	    Ext2Error::Io { context, source } => write!(f, "IO error {}: {}", context, source)
+	}
+    }
+}
+
+impl Error for Ext2Error {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+	match self {
+	    Ext2Error::Io { source, .. } => Some(source),
+	    _ => None
+	}
+    }
+}
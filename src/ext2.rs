@@ -3,7 +3,44 @@ pub mod bgd;
 pub mod bitmap;
 pub mod inode;
 pub mod directory;
+pub mod features;
 
+// The unit of i_blocks, and the default device sector size
 pub const SECTOR_SIZE : u32 = 512;
-pub const BLOCK_SIZE : u32 = 1024;
+pub const MIN_BLOCK_SIZE : u32 = 1024;
+pub const MAX_BLOCK_SIZE : u32 = 4096;
 
+
+use crate::error::{Ext2Error, Result};
+
+// Little-endian field readers shared by the structure parsers. Each one
+// consumes its bytes from the front of the slice.
+pub(crate) fn read_bytes<const N : usize>(buf : &mut &[u8]) -> Result<[u8; N]> {
+    if buf.len() < N {
+	return Err(Ext2Error::Corrupt(String::from("Truncated structure")));
+    }
+    let mut b = [0; N];
+    b.copy_from_slice(&buf[.. N]);
+    *buf = &buf[N ..];
+    Ok(b)
+}
+
+pub(crate) fn read_u8(buf : &mut &[u8]) -> Result<u8> {
+    Ok(u8::from_le_bytes(read_bytes(buf)?))
+}
+
+pub(crate) fn read_u16(buf : &mut &[u8]) -> Result<u16> {
+    Ok(u16::from_le_bytes(read_bytes(buf)?))
+}
+
+pub(crate) fn read_u32(buf : &mut &[u8]) -> Result<u32> {
+    Ok(u32::from_le_bytes(read_bytes(buf)?))
+}
+
+pub(crate) fn read_u64(buf : &mut &[u8]) -> Result<u64> {
+    Ok(u64::from_le_bytes(read_bytes(buf)?))
+}
+
+pub(crate) fn read_u128(buf : &mut &[u8]) -> Result<u128> {
+    Ok(u128::from_le_bytes(read_bytes(buf)?))
+}
@@ -1,11 +1,13 @@
-use std::io::prelude::*;
-use std::fs::File;
 use std::convert::TryInto;
 
 use crate::ext2::superblock::Superblock;
+use crate::ext2::{read_u16, read_u32};
+use crate::device::BlockDevice;
+use crate::error::{Ext2Error, Result};
 
 #[derive(Default)]
 #[derive(Debug)]
+#[allow(clippy::upper_case_acronyms)]
 pub struct BGD {
     pub bg_block_bitmap : u32,
     pub bg_inode_bitmap : u32,
@@ -15,36 +17,70 @@ pub struct BGD {
     pub bg_used_dirs_count : u16,
     pub idx : u32
 }
-const BGD_PADDING : i64 = 14;
+const BGD_PADDING : usize = 14;
 pub const BGD_SIZE : u32 = 32;
 
 impl BGD {
-    pub fn write(&self, mut file : &File) -> std::io::Result<()> {
-	file.write(&self.bg_block_bitmap.to_le_bytes())?;
-	file.write(&self.bg_inode_bitmap.to_le_bytes())?;
-	file.write(&self.bg_inode_table.to_le_bytes())?;
-	file.write(&self.bg_free_blocks_count.to_le_bytes())?;
-	file.write(&self.bg_free_inodes_count.to_le_bytes())?;
-	file.write(&self.bg_used_dirs_count.to_le_bytes())?;
-	file.seek(std::io::SeekFrom::Current(BGD_PADDING))?;
-	Ok(())
+    pub fn read(dev : &mut dyn BlockDevice, offset : u64, id : u32) -> Result<Self> {
+	let mut raw = [0; BGD_SIZE as usize];
+	dev.read_at(offset, &mut raw).map_err(|e| Ext2Error::io(format!("reading group descriptor {}", id), e))?;
+	let mut file = &raw[..];
+	Ok(BGD {
+	    bg_block_bitmap: read_u32(&mut file)?,
+	    bg_inode_bitmap: read_u32(&mut file)?,
+	    bg_inode_table: read_u32(&mut file)?,
+	    bg_free_blocks_count: read_u16(&mut file)?,
+	    bg_free_inodes_count: read_u16(&mut file)?,
+	    bg_used_dirs_count: read_u16(&mut file)?,
+	    idx: id
+	})
     }
-    pub fn new(sb: &Superblock, id: u32) -> Self {
+    pub fn write(&self, dev : &mut dyn BlockDevice, offset : u64) -> Result<()> {
+	let mut file : Vec<u8> = Vec::with_capacity(BGD_SIZE as usize);
+	file.extend_from_slice(&self.bg_block_bitmap.to_le_bytes());
+	file.extend_from_slice(&self.bg_inode_bitmap.to_le_bytes());
+	file.extend_from_slice(&self.bg_inode_table.to_le_bytes());
+	file.extend_from_slice(&self.bg_free_blocks_count.to_le_bytes());
+	file.extend_from_slice(&self.bg_free_inodes_count.to_le_bytes());
+	file.extend_from_slice(&self.bg_used_dirs_count.to_le_bytes());
+	file.extend_from_slice(&[0; BGD_PADDING]);
+	dev.write_at(offset, &file).map_err(|e| Ext2Error::io(format!("writing group descriptor {}", self.idx), e))
+    }
+    pub fn new(sb: &Superblock, id: u32) -> Result<Self> {
+	let bgd = BGD { idx: id, ..Default::default() };
+	BGD::new_at(sb, id, bgd.descriptors_end(sb))
+    }
+
+    // Places the bitmaps and inode table from the given block onwards
+    pub fn new_at(sb: &Superblock, id: u32, block: u32) -> Result<Self> {
 	let mut bgd : BGD = Default::default();
-	let num_groups = (sb.s_blocks_count + 511) / sb.s_blocks_per_group;
-	bgd.bg_block_bitmap = sb.s_first_data_block + sb.s_blocks_per_group * id + 2;
+	bgd.idx = id;
+	bgd.bg_block_bitmap = block;
 	bgd.bg_inode_bitmap = bgd.bg_block_bitmap + 1;
 	bgd.bg_inode_table = bgd.bg_inode_bitmap + 1;
-	if id == num_groups - 1 {
-	    bgd.bg_free_blocks_count = (sb.s_blocks_count - sb.s_blocks_per_group * (num_groups - 1)).try_into().unwrap();
-	} else {
-	    bgd.bg_free_blocks_count = sb.s_blocks_per_group.try_into().unwrap();
-	}
-	bgd.bg_free_inodes_count = sb.s_inodes_per_group.try_into().unwrap();
-	bgd.idx = id;
-	bgd
+	bgd.bg_free_blocks_count = match bgd.num_blocks(sb).try_into() {
+	    Ok(count) => count,
+	    Err(_) => return Err(Ext2Error::Overflow(format!("{} blocks in group {}", bgd.num_blocks(sb), id)))
+	};
+	bgd.bg_free_inodes_count = match sb.s_inodes_per_group.try_into() {
+	    Ok(count) => count,
+	    Err(_) => return Err(Ext2Error::Overflow(format!("{} inodes in group {}", sb.s_inodes_per_group, id)))
+	};
+	Ok(bgd)
     }
     pub fn get_start(&self, sb : &Superblock) -> u32 {
 	self.idx * sb.s_blocks_per_group + sb.s_first_data_block
     }
+    // The first block after the superblock and descriptor table, if the
+    // group has them
+    pub fn descriptors_end(&self, sb : &Superblock) -> u32 {
+	if sb.has_super(self.idx) {
+	    self.get_start(sb) + 1 + sb.gdt_blocks()
+	} else {
+	    self.get_start(sb)
+	}
+    }
+    pub fn num_blocks(&self, sb : &Superblock) -> u32 {
+	std::cmp::min(sb.s_blocks_per_group, sb.s_blocks_count - self.get_start(sb))
+    }
 }
@@ -1,16 +1,13 @@
-use std::io::prelude::*;
-use std::fs::File;
 use std::vec::Vec;
-use std::convert::TryInto;
 
-use crate::ext2::BLOCK_SIZE;
-
-use crate::ext2::superblock::Superblock;
-use crate::ext2::bgd::BGD;
+use crate::device::BlockDevice;
+use crate::error::{Ext2Error, Result};
 
 #[derive(Debug)]
 pub struct Bitmap {
-    values: Vec<u8>
+    values: Vec<u8>,
+    // No bit before this one is clear
+    next_free: u32
 }
 
 impl Bitmap {
@@ -19,6 +16,7 @@ impl Bitmap {
 	    self.values[idx as usize / 8] |= 1 << (idx % 8);
 	} else {
 	    self.values[idx as usize / 8] &= !(1 << (idx % 8));
+	    self.next_free = std::cmp::min(self.next_free, idx);
 	}
     }
 
@@ -27,35 +25,62 @@ impl Bitmap {
     }
 
     pub fn len(&self) -> u32 {
-	(self.values.len() * 8).try_into().unwrap()
+	self.values.len() as u32 * 8
     }
 
     pub fn new(len: u32) -> Self {
 	Self {
-	    values: vec![0; (len as usize + 7) / 8]
+	    values: vec![0; (len as usize).div_ceil(8)],
+	    next_free: 0
 	}
     }
 
-    pub fn alloc(&mut self, bgd : &mut BGD, sb : &mut Superblock) -> Option<u32> {
-	for i in 0 .. self.len() {
-	    if ! self.get(i) {
-		bgd.bg_free_blocks_count -= 1;
-		sb.s_free_blocks_count -= 1;
-		self.set(i, true);
-		if bgd.idx == 0 {
-		    return Some(i + 1);
-		}
-		return Some(i);
-	    }
-	}
-	None
+    pub fn is_empty(&self) -> bool {
+	self.values.is_empty()
+    }
+
+    // Scans a byte at a time from the first bit that might be clear
+    pub fn first_free(&mut self) -> Option<u32> {
+	let start = self.next_free as usize / 8;
+	let byte = start + self.values[start ..].iter().position(|&b| b != 0xff)?;
+	let bit = (0 .. 8).find(|&i| self.values[byte] & (1 << i) == 0)?;
+	self.next_free = byte as u32 * 8 + bit;
+	Some(self.next_free)
+    }
+
+    pub fn read(dev : &mut dyn BlockDevice, block : u32, len : u32, block_size : u32) -> Result<Self> {
+	let mut buf = vec![0; block_size as usize];
+	dev.read_block(block as u64, &mut buf).map_err(|e| Ext2Error::io(format!("reading bitmap block {}", block), e))?;
+	buf.truncate((len as usize).div_ceil(8));
+	Ok(Self {
+	    values: buf,
+	    next_free: 0
+	})
     }
 
-    pub fn write(&self, mut file : &File) -> std::io::Result<()> {
-	file.write(&self.values)?;
-	for _ in 0 .. BLOCK_SIZE as usize - self.values.len() {
-	    file.write(&[0xff])?;
+    pub fn write(&self, dev : &mut dyn BlockDevice, block : u32, block_size : u32) -> Result<()> {
+	let mut buf = self.values.clone();
+	buf.resize(block_size as usize, 0xff);
+	dev.write_block(block as u64, &buf).map_err(|e| Ext2Error::io(format!("writing bitmap block {}", block), e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn first_free_follows_set_and_clear() {
+	let mut bmap = Bitmap::new(64);
+	for i in 0 .. 20 {
+	    assert_eq!(bmap.first_free(), Some(i));
+	    bmap.set(i, true);
+	}
+	bmap.set(3, false);
+	assert_eq!(bmap.first_free(), Some(3));
+	for i in 0 .. 64 {
+	    bmap.set(i, true);
 	}
-	Ok(())
+	assert_eq!(bmap.first_free(), None);
     }
 }
@@ -1,67 +1,228 @@
-use std::io::prelude::*;
-use std::fs::File;
+use crate::ext2::{read_u8, read_u16, read_u32};
+use crate::ext2::inode::{EXT2_S_IFMT, EXT2_S_IFREG, EXT2_S_IFDIR, EXT2_S_IFCHR, EXT2_S_IFBLK};
+use crate::ext2::inode::{EXT2_S_IFIFO, EXT2_S_IFSOCK, EXT2_S_IFLNK};
+use crate::device::BlockDevice;
+use crate::error::{Ext2Error, Result};
 
-use crate::ext2::BLOCK_SIZE;
+const DIRENT_HEADER_SIZE : u16 = 8;
+pub const EXT2_NAME_LEN : usize = 255;
+
+// Entry types, only filled in with the filetype feature
+pub const EXT2_FT_UNKNOWN : u8 = 0;
+pub const EXT2_FT_REG_FILE : u8 = 1;
+pub const EXT2_FT_DIR : u8 = 2;
+pub const EXT2_FT_CHRDEV : u8 = 3;
+pub const EXT2_FT_BLKDEV : u8 = 4;
+pub const EXT2_FT_FIFO : u8 = 5;
+pub const EXT2_FT_SOCK : u8 = 6;
+pub const EXT2_FT_SYMLINK : u8 = 7;
+
+pub fn file_type(mode : u16) -> u8 {
+    match mode & EXT2_S_IFMT {
+	EXT2_S_IFREG => EXT2_FT_REG_FILE,
+	EXT2_S_IFDIR => EXT2_FT_DIR,
+	EXT2_S_IFCHR => EXT2_FT_CHRDEV,
+	EXT2_S_IFBLK => EXT2_FT_BLKDEV,
+	EXT2_S_IFIFO => EXT2_FT_FIFO,
+	EXT2_S_IFSOCK => EXT2_FT_SOCK,
+	EXT2_S_IFLNK => EXT2_FT_SYMLINK,
+	_ => EXT2_FT_UNKNOWN
+    }
+}
+
+// Entries are padded out to a multiple of 4 bytes
+fn entry_size(name_len : usize) -> u16 {
+    (DIRENT_HEADER_SIZE + name_len as u16).next_multiple_of(4)
+}
+
+pub fn validate_name(name : &str) -> Result<()> {
+    if name.is_empty() || name.len() > EXT2_NAME_LEN || name == "." || name == ".." || name.contains(['/', '\0']) {
+	return Err(Ext2Error::InvalidInput(format!("Invalid file name {:?}", name)));
+    }
+    Ok(())
+}
 
 #[derive(Debug)]
-struct DirectoryEntry {
-    inode : u32,
-    rec_len : u16,
-    name_len : u8,
-    file_type : u8,
-    name : String
+pub struct DirectoryEntry {
+    pub inode : u32,
+    pub rec_len : u16,
+    pub name_len : u8,
+    pub file_type : u8,
+    pub name : String
 }
 
 impl DirectoryEntry {
-    pub fn write(&self, mut file : &File) -> std::io::Result<()> {
-	println!("{:#?}", self);
-	file.write(&self.inode.to_le_bytes())?;	
-	file.write(&self.rec_len.to_le_bytes())?;	
-	file.write(&self.name_len.to_le_bytes())?;	
-	file.write(&self.file_type.to_le_bytes())?;	
-	file.write(&self.name.as_bytes())?;
-	file.seek(std::io::SeekFrom::Current((self.rec_len - 8 - self.name_len as u16) as i64))?;
-	Ok(())
+    pub fn read(file : &mut &[u8]) -> Result<Self> {
+	let remaining = file.len();
+	let inode = read_u32(file)?;
+	let rec_len = read_u16(file)?;
+	let name_len = read_u8(file)?;
+	let file_type = read_u8(file)?;
+	if rec_len < DIRENT_HEADER_SIZE + name_len as u16 || rec_len as usize > remaining {
+	    return Err(Ext2Error::Corrupt(format!("Bad directory entry length {}", rec_len)));
+	}
+	let name = match String::from_utf8(file[.. name_len as usize].to_vec()) {
+	    Ok(name) => name,
+	    Err(_) => return Err(Ext2Error::Corrupt(String::from("Directory entry name is not UTF-8")))
+	};
+	*file = &file[name_len as usize ..];
+	*file = &file[(rec_len - DIRENT_HEADER_SIZE - name_len as u16) as usize ..];
+	Ok(DirectoryEntry {
+	    inode,
+	    rec_len,
+	    name_len,
+	    file_type,
+	    name
+	})
+    }
+
+    pub fn write(&self, file : &mut Vec<u8>) {
+	file.extend_from_slice(&self.inode.to_le_bytes());
+	file.extend_from_slice(&self.rec_len.to_le_bytes());
+	file.extend_from_slice(&self.name_len.to_le_bytes());
+	file.extend_from_slice(&self.file_type.to_le_bytes());
+	file.extend_from_slice(self.name.as_bytes());
+	file.extend_from_slice(&vec![0; (self.rec_len - DIRENT_HEADER_SIZE - self.name_len as u16) as usize]);
     }
 }
 
 #[derive(Debug)]
 pub struct Directory {
-    entries : Vec<DirectoryEntry>
+    entries : Vec<DirectoryEntry>,
+    block_size : u32
 }
 
 impl Directory {
-    pub fn new(inode : u32, parent_inode : u32) -> Self {
+    // With filetype, "." and ".." are marked as directories
+    pub fn new(inode : u32, parent_inode : u32, block_size : u32, filetype : bool) -> Self {
 	let mut entries : Vec<DirectoryEntry> = Vec::new();
+	let file_type = if filetype { EXT2_FT_DIR } else { EXT2_FT_UNKNOWN };
 	let dot = DirectoryEntry {
-	    inode: inode,
-	    rec_len: 12,
+	    inode,
+	    rec_len: entry_size(1),
 	    name_len: 1,
-	    file_type: 0,
+	    file_type,
 	    name: String::from(".")
 	};
 	entries.push(dot);
 	let dotdot = DirectoryEntry {
 	    inode: parent_inode,
-	    rec_len: BLOCK_SIZE as u16 - 12,
+	    rec_len: block_size as u16 - entry_size(1),
 	    name_len: 2,
-	    file_type: 0,
+	    file_type,
 	    name: String::from("..")
 	};
 	entries.push(dotdot);
 	Directory {
-	    entries: entries
+	    entries,
+	    block_size
+	}
+    }
+
+    // Reads a directory from its data blocks, in logical order
+    pub fn read(dev : &mut dyn BlockDevice, blocks : &[u32], block_size : u32) -> Result<Self> {
+	let mut entries : Vec<DirectoryEntry> = Vec::new();
+	let mut buf = vec![0; block_size as usize];
+	for &block in blocks {
+	    dev.read_block(block as u64, &mut buf).map_err(|e| Ext2Error::io(format!("reading directory block {}", block), e))?;
+	    let mut file = &buf[..];
+	    while !file.is_empty() {
+		entries.push(DirectoryEntry::read(&mut file)?);
+	    }
+	}
+	Ok(Directory {
+	    entries,
+	    block_size
+	})
+    }
+
+    // New entries take over the slack at the end of the last block, or start
+    // a new block if there isn't enough. Entries never cross a block boundary.
+    pub fn add(&mut self, inode : u32, name : &str, file_type : u8) -> Result<()> {
+	validate_name(name)?;
+	if self.find(name).is_some() {
+	    return Err(Ext2Error::InvalidInput(format!("{} already exists", name)));
+	}
+	let needed = entry_size(name.len());
+	let mut rec_len = self.block_size as u16;
+	if let Some(last) = self.entries.last_mut() {
+	    let last_size = entry_size(last.name_len as usize);
+	    if last.rec_len - last_size >= needed {
+		rec_len = last.rec_len - last_size;
+		last.rec_len = last_size;
+	    }
 	}
+	self.entries.push(DirectoryEntry {
+	    inode,
+	    rec_len,
+	    name_len: name.len() as u8,
+	    file_type,
+	    name: String::from(name)
+	});
+	Ok(())
+    }
+
+    pub fn find(&self, name : &str) -> Option<&DirectoryEntry> {
+	self.entries.iter().find(|e| e.inode != 0 && e.name == name)
     }
 
-    pub fn write(&self, file : &File) -> std::io::Result<()> {
+    pub fn entries(&self) -> &[DirectoryEntry] {
+	&self.entries
+    }
+
+    // Writes the directory to its data blocks, in logical order
+    pub fn write(&self, dev : &mut dyn BlockDevice, blocks : &[u32]) -> Result<()> {
+	if blocks.len() != self.num_blocks() as usize {
+	    return Err(Ext2Error::InvalidInput(format!("Directory needs {} blocks, not {}", self.num_blocks(), blocks.len())));
+	}
+	let mut buf : Vec<u8> = Vec::with_capacity(self.len() as usize);
 	for entry in &self.entries {
-	    entry.write(&file)?;
+	    entry.write(&mut buf);
+	}
+	for (&block, chunk) in blocks.iter().zip(buf.chunks(self.block_size as usize)) {
+	    dev.write_block(block as u64, chunk).map_err(|e| Ext2Error::io(format!("writing directory block {}", block), e))?;
 	}
 	Ok(())
     }
 
+    // Size in bytes, which is always a whole number of blocks
     pub fn len(&self) -> u32 {
 	self.entries.iter().fold(0, |acc, x| acc + x.rec_len as u32)
     }
+
+    pub fn num_blocks(&self) -> u32 {
+	self.len() / self.block_size
+    }
+
+    pub fn is_empty(&self) -> bool {
+	self.entries.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn add_spills_into_second_block() {
+	let mut dir = Directory::new(2, 2, 1024, true);
+	// "." and ".." take 24 bytes and each of these 208, so the fifth one
+	// doesn't fit in the first block
+	let names : Vec<String> = (0 .. 5).map(|i| format!("{}{}", i, "x".repeat(199))).collect();
+	for (i, name) in names.iter().enumerate() {
+	    dir.add(12 + i as u32, name, EXT2_FT_REG_FILE).unwrap();
+	}
+	assert_eq!(dir.num_blocks(), 2);
+	let first_block : u32 = dir.entries()[.. 6].iter().map(|e| e.rec_len as u32).sum();
+	assert_eq!(first_block, 1024);
+	assert_eq!(dir.entries()[6].rec_len, 1024);
+
+	let mut dev : Vec<u8> = Vec::new();
+	dir.write(&mut dev, &[1, 2]).unwrap();
+	let read = Directory::read(&mut dev, &[1, 2], 1024).unwrap();
+	assert_eq!(read.num_blocks(), 2);
+	for (i, name) in names.iter().enumerate() {
+	    assert_eq!(read.find(name).unwrap().inode, 12 + i as u32);
+	}
+    }
 }
@@ -0,0 +1,277 @@
+use crate::error::{Ext2Error, Result};
+
+// Which superblock mask a feature lives in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FeatureKind {
+    // Readers that don't know the feature can still write the filesystem
+    Compat,
+    // Readers that don't know the feature can't mount it at all
+    Incompat,
+    // Readers that don't know the feature can only mount it read-only
+    RoCompat
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Feature {
+    DirPrealloc,
+    ImagicInodes,
+    HasJournal,
+    ExtAttr,
+    ResizeInode,
+    DirIndex,
+    Compression,
+    Filetype,
+    NeedsRecovery,
+    JournalDev,
+    MetaBg,
+    SparseSuper,
+    LargeFile,
+    BtreeDir
+}
+
+impl Feature {
+    pub const ALL : [Feature; 14] = [
+	Feature::DirPrealloc,
+	Feature::ImagicInodes,
+	Feature::HasJournal,
+	Feature::ExtAttr,
+	Feature::ResizeInode,
+	Feature::DirIndex,
+	Feature::Compression,
+	Feature::Filetype,
+	Feature::NeedsRecovery,
+	Feature::JournalDev,
+	Feature::MetaBg,
+	Feature::SparseSuper,
+	Feature::LargeFile,
+	Feature::BtreeDir
+    ];
+
+    // The names mke2fs uses for -O
+    pub fn name(self) -> &'static str {
+	match self {
+	    Feature::DirPrealloc => "dir_prealloc",
+	    Feature::ImagicInodes => "imagic_inodes",
+	    Feature::HasJournal => "has_journal",
+	    Feature::ExtAttr => "ext_attr",
+	    Feature::ResizeInode => "resize_inode",
+	    Feature::DirIndex => "dir_index",
+	    Feature::Compression => "compression",
+	    Feature::Filetype => "filetype",
+	    Feature::NeedsRecovery => "needs_recovery",
+	    Feature::JournalDev => "journal_dev",
+	    Feature::MetaBg => "meta_bg",
+	    Feature::SparseSuper => "sparse_super",
+	    Feature::LargeFile => "large_file",
+	    Feature::BtreeDir => "btree_dir"
+	}
+    }
+
+    pub fn from_name(name : &str) -> Option<Feature> {
+	Feature::ALL.iter().copied().find(|f| f.name() == name)
+    }
+
+    pub fn kind(self) -> FeatureKind {
+	match self {
+	    Feature::DirPrealloc | Feature::ImagicInodes | Feature::HasJournal |
+	    Feature::ExtAttr | Feature::ResizeInode | Feature::DirIndex => FeatureKind::Compat,
+	    Feature::Compression | Feature::Filetype | Feature::NeedsRecovery |
+	    Feature::JournalDev | Feature::MetaBg => FeatureKind::Incompat,
+	    Feature::SparseSuper | Feature::LargeFile | Feature::BtreeDir => FeatureKind::RoCompat
+	}
+    }
+
+    pub fn mask(self) -> u32 {
+	match self {
+	    Feature::DirPrealloc => 0x0001,
+	    Feature::ImagicInodes => 0x0002,
+	    Feature::HasJournal => 0x0004,
+	    Feature::ExtAttr => 0x0008,
+	    Feature::ResizeInode => 0x0010,
+	    Feature::DirIndex => 0x0020,
+	    Feature::Compression => 0x0001,
+	    Feature::Filetype => 0x0002,
+	    Feature::NeedsRecovery => 0x0004,
+	    Feature::JournalDev => 0x0008,
+	    Feature::MetaBg => 0x0010,
+	    Feature::SparseSuper => 0x0001,
+	    Feature::LargeFile => 0x0002,
+	    Feature::BtreeDir => 0x0004
+	}
+    }
+
+    // Features that only make sense alongside another one
+    fn requires(self) -> Option<Feature> {
+	match self {
+	    Feature::ResizeInode => Some(Feature::SparseSuper),
+	    Feature::NeedsRecovery => Some(Feature::HasJournal),
+	    _ => None
+	}
+    }
+
+    fn conflicts(self) -> Option<Feature> {
+	match self {
+	    Feature::MetaBg => Some(Feature::ResizeInode),
+	    _ => None
+	}
+    }
+
+    // Whether the builder knows how to lay out a filesystem with the feature
+    fn supported(self) -> bool {
+	matches!(self, Feature::ExtAttr | Feature::DirIndex | Feature::Filetype | Feature::SparseSuper | Feature::LargeFile)
+    }
+}
+
+// The three feature masks of a superblock
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Features {
+    pub compat : u32,
+    pub incompat : u32,
+    pub ro_compat : u32
+}
+
+impl Features {
+    fn mask_mut(&mut self, kind : FeatureKind) -> &mut u32 {
+	match kind {
+	    FeatureKind::Compat => &mut self.compat,
+	    FeatureKind::Incompat => &mut self.incompat,
+	    FeatureKind::RoCompat => &mut self.ro_compat
+	}
+    }
+
+    pub fn contains(&self, feature : Feature) -> bool {
+	let mask = match feature.kind() {
+	    FeatureKind::Compat => self.compat,
+	    FeatureKind::Incompat => self.incompat,
+	    FeatureKind::RoCompat => self.ro_compat
+	};
+	mask & feature.mask() != 0
+    }
+
+    pub fn insert(&mut self, feature : Feature) {
+	*self.mask_mut(feature.kind()) |= feature.mask();
+    }
+
+    pub fn remove(&mut self, feature : Feature) {
+	*self.mask_mut(feature.kind()) &= !feature.mask();
+    }
+
+    pub fn is_empty(&self) -> bool {
+	*self == Features::default()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = Feature> + '_ {
+	Feature::ALL.iter().copied().filter(move |&f| self.contains(f))
+    }
+
+    // Applies a comma separated list like mke2fs -O. A leading ^ clears a
+    // feature, and "none" clears them all.
+    pub fn edit(&mut self, spec : &str) -> Result<()> {
+	for name in spec.split(',').map(str::trim).filter(|name| !name.is_empty()) {
+	    if name == "none" {
+		*self = Features::default();
+		continue;
+	    }
+	    let (clear, name) = match name.strip_prefix('^') {
+		Some(name) => (true, name),
+		None => (false, name)
+	    };
+	    let feature = match Feature::from_name(name) {
+		Some(feature) => feature,
+		None => return Err(Ext2Error::InvalidInput(format!("Unknown feature {}", name)))
+	    };
+	    if clear {
+		self.remove(feature);
+	    } else {
+		self.insert(feature);
+	    }
+	}
+	Ok(())
+    }
+
+    // Checks the features fit together and that we can make them
+    pub fn validate(&self) -> Result<()> {
+	for feature in self.iter() {
+	    if let Some(needed) = feature.requires() {
+		if !self.contains(needed) {
+		    return Err(Ext2Error::InvalidInput(format!("Feature {} requires {}", feature.name(), needed.name())));
+		}
+	    }
+	    if let Some(other) = feature.conflicts() {
+		if self.contains(other) {
+		    return Err(Ext2Error::InvalidInput(format!("Features {} and {} can't be used together", feature.name(), other.name())));
+		}
+	    }
+	}
+	match self.iter().find(|f| !f.supported()) {
+	    Some(feature) => Err(Ext2Error::InvalidInput(format!("Feature {} is not supported", feature.name()))),
+	    None => Ok(())
+	}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn edit_sets_and_clears() {
+	let mut features = Features::default();
+	features.edit("filetype,sparse_super").unwrap();
+	assert!(features.contains(Feature::Filetype));
+	features.edit("filetype,^filetype").unwrap();
+	assert!(!features.contains(Feature::Filetype));
+	assert!(features.contains(Feature::SparseSuper));
+	features.edit("none").unwrap();
+	assert!(features.is_empty());
+	features.edit("none,large_file").unwrap();
+	assert_eq!(features.iter().collect::<Vec<Feature>>(), [Feature::LargeFile]);
+	assert!(matches!(features.edit("filetype,bogus"), Err(Ext2Error::InvalidInput(_))));
+    }
+
+    #[test]
+    fn validate_dependencies() {
+	let mut features = Features::default();
+	features.edit("resize_inode").unwrap();
+	assert!(matches!(features.validate(), Err(Ext2Error::InvalidInput(e)) if e.contains("requires sparse_super")));
+
+	let mut features = Features::default();
+	features.edit("meta_bg,resize_inode,sparse_super").unwrap();
+	assert!(matches!(features.validate(), Err(Ext2Error::InvalidInput(e)) if e.contains("can't be used together")));
+
+	let mut features = Features::default();
+	features.edit("ext_attr,dir_index,filetype,sparse_super,large_file").unwrap();
+	assert!(features.validate().is_ok());
+    }
+
+    #[test]
+    fn bits_land_in_their_mask() {
+	let expected = [
+	    (Feature::DirPrealloc, Features { compat: 0x0001, ..Default::default() }),
+	    (Feature::ImagicInodes, Features { compat: 0x0002, ..Default::default() }),
+	    (Feature::HasJournal, Features { compat: 0x0004, ..Default::default() }),
+	    (Feature::ExtAttr, Features { compat: 0x0008, ..Default::default() }),
+	    (Feature::ResizeInode, Features { compat: 0x0010, ..Default::default() }),
+	    (Feature::DirIndex, Features { compat: 0x0020, ..Default::default() }),
+	    (Feature::Compression, Features { incompat: 0x0001, ..Default::default() }),
+	    (Feature::Filetype, Features { incompat: 0x0002, ..Default::default() }),
+	    (Feature::NeedsRecovery, Features { incompat: 0x0004, ..Default::default() }),
+	    (Feature::JournalDev, Features { incompat: 0x0008, ..Default::default() }),
+	    (Feature::MetaBg, Features { incompat: 0x0010, ..Default::default() }),
+	    (Feature::SparseSuper, Features { ro_compat: 0x0001, ..Default::default() }),
+	    (Feature::LargeFile, Features { ro_compat: 0x0002, ..Default::default() }),
+	    (Feature::BtreeDir, Features { ro_compat: 0x0004, ..Default::default() })
+	];
+	for (feature, masks) in expected {
+	    let mut features = Features::default();
+	    features.insert(feature);
+	    assert_eq!(features, masks, "{}", feature.name());
+	}
+	for feature in Feature::ALL {
+	    let mut features = Features::default();
+	    features.insert(feature);
+	    assert_eq!(features.iter().collect::<Vec<Feature>>(), [feature]);
+	    assert_eq!(Feature::from_name(feature.name()), Some(feature));
+	}
+    }
+}
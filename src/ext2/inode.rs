@@ -1,7 +1,9 @@
-use std::io::prelude::*;
-use std::fs::File;
 
-#[derive(Debug)]
+use crate::ext2::{read_u8, read_u16, read_u32};
+use crate::device::BlockDevice;
+use crate::error::{Ext2Error, Result};
+
+#[derive(Debug, Default, Clone)]
 pub struct Inode {
     pub i_mode : u16,
     pub i_uid : u16,
@@ -15,7 +17,7 @@ pub struct Inode {
     pub i_blocks : u32,
     pub i_flags : u32,
     pub i_osd1 : u32,
-    pub i_block : [u32; 15],
+    pub i_block : [u32; EXT2_N_BLOCKS],
     pub i_generation : u32,
     pub i_file_acl : u32,
     pub i_dir_acl : u32,
@@ -24,79 +26,168 @@ pub struct Inode {
     pub l_i_fsize : u8,
     pub reserved : u16,
     pub l_i_uid_high : u16,
-    pub l_i_gid_high : u16
+    pub l_i_gid_high : u16,
+    // Only in inodes bigger than the old 128 bytes. The *_extra times hold
+    // nanoseconds shifted left by two, and the epoch in the low two bits.
+    pub i_extra_isize : u16,
+    pub i_checksum_hi : u16,
+    pub i_ctime_extra : u32,
+    pub i_mtime_extra : u32,
+    pub i_atime_extra : u32,
+    pub i_crtime : u32,
+    pub i_crtime_extra : u32,
+    pub i_version_hi : u32,
+    pub i_projid : u32
 }
 
-pub const INODE_PADDING : i64 = 4;
-pub const INODE_SIZE : u32 = 128;
+pub const EXT2_S_IFMT : u16 = 0xf000;
+pub const EXT2_S_IFSOCK : u16 = 0xc000;
+pub const EXT2_S_IFLNK : u16 = 0xa000;
+pub const EXT2_S_IFREG : u16 = 0x8000;
+pub const EXT2_S_IFBLK : u16 = 0x6000;
+pub const EXT2_S_IFDIR : u16 = 0x4000;
+pub const EXT2_S_IFCHR : u16 = 0x2000;
+pub const EXT2_S_IFIFO : u16 = 0x1000;
+
+// Number of block pointers in i_block, and how many point directly at data
+pub const EXT2_N_BLOCKS : usize = 15;
+pub const EXT2_NDIR_BLOCKS : usize = 12;
+pub const EXT2_IND_BLOCK : usize = 12;
+pub const EXT2_DIND_BLOCK : usize = 13;
+pub const EXT2_TIND_BLOCK : usize = 14;
+
+pub const INODE_PADDING : usize = 4;
+pub const EXT2_GOOD_OLD_INODE_SIZE : u32 = 128;
+// The extra fields this writes after the old inode. The rest of a large
+// inode is left for extended attributes.
+pub const EXT2_INODE_EXTRA_SIZE : u16 = 32;
 
 impl Inode {
-    pub fn write(&self, mut file : &File) -> std::io::Result<()> {
-	file.write(&self.i_mode.to_le_bytes())?;
-	file.write(&self.i_uid.to_le_bytes())?;
-	file.write(&self.i_size.to_le_bytes())?;
-	file.write(&self.i_atime.to_le_bytes())?;
-	file.write(&self.i_ctime.to_le_bytes())?;
-	file.write(&self.i_mtime.to_le_bytes())?;
-	file.write(&self.i_dtime.to_le_bytes())?;
-	file.write(&self.i_gid.to_le_bytes())?;
-	file.write(&self.i_links_count.to_le_bytes())?;
-	file.write(&self.i_blocks.to_le_bytes())?;
-	file.write(&self.i_flags.to_le_bytes())?;
-	file.write(&self.i_osd1.to_le_bytes())?;
+    pub fn read(dev : &mut dyn BlockDevice, offset : u64, inode_size : u32) -> Result<Self> {
+	let mut raw = vec![0; inode_size as usize];
+	dev.read_at(offset, &mut raw).map_err(|e| Ext2Error::io(format!("reading inode at {}", offset), e))?;
+	let mut file = &raw[..];
+	let mut inode = Inode {
+	    i_mode: read_u16(&mut file)?,
+	    i_uid: read_u16(&mut file)?,
+	    i_size: read_u32(&mut file)?,
+	    i_atime: read_u32(&mut file)?,
+	    i_ctime: read_u32(&mut file)?,
+	    i_mtime: read_u32(&mut file)?,
+	    i_dtime: read_u32(&mut file)?,
+	    i_gid: read_u16(&mut file)?,
+	    i_links_count: read_u16(&mut file)?,
+	    i_blocks: read_u32(&mut file)?,
+	    i_flags: read_u32(&mut file)?,
+	    i_osd1: read_u32(&mut file)?,
+	    i_block: {
+		let mut x = [0; EXT2_N_BLOCKS];
+		for y in x.iter_mut() {
+		    *y = read_u32(&mut file)?;
+		}
+		x
+	    },
+	    i_generation: read_u32(&mut file)?,
+	    i_file_acl: read_u32(&mut file)?,
+	    i_dir_acl: read_u32(&mut file)?,
+	    i_faddr: read_u32(&mut file)?,
+	    l_i_frag: read_u8(&mut file)?,
+	    l_i_fsize: read_u8(&mut file)?,
+	    reserved: read_u16(&mut file)?,
+	    l_i_uid_high: read_u16(&mut file)?,
+	    l_i_gid_high: read_u16(&mut file)?,
+	    ..Default::default()
+	};
+	if inode_size > EXT2_GOOD_OLD_INODE_SIZE {
+	    let raw = &raw[EXT2_GOOD_OLD_INODE_SIZE as usize ..];
+	    inode.i_extra_isize = read_u16(&mut &raw[..])?;
+	    // Fields past i_extra_isize aren't in use, so read them as zero
+	    let mut extra = [0; EXT2_INODE_EXTRA_SIZE as usize];
+	    let len = std::cmp::min(inode.i_extra_isize as usize, std::cmp::min(extra.len(), raw.len()));
+	    extra[.. len].copy_from_slice(&raw[.. len]);
+	    let mut file = &extra[2 ..];
+	    inode.i_checksum_hi = read_u16(&mut file)?;
+	    inode.i_ctime_extra = read_u32(&mut file)?;
+	    inode.i_mtime_extra = read_u32(&mut file)?;
+	    inode.i_atime_extra = read_u32(&mut file)?;
+	    inode.i_crtime = read_u32(&mut file)?;
+	    inode.i_crtime_extra = read_u32(&mut file)?;
+	    inode.i_version_hi = read_u32(&mut file)?;
+	    inode.i_projid = read_u32(&mut file)?;
+	}
+	Ok(inode)
+    }
+
+    pub fn write(&self, dev : &mut dyn BlockDevice, offset : u64, inode_size : u32) -> Result<()> {
+	let mut file : Vec<u8> = Vec::with_capacity(inode_size as usize);
+	file.extend_from_slice(&self.i_mode.to_le_bytes());
+	file.extend_from_slice(&self.i_uid.to_le_bytes());
+	file.extend_from_slice(&self.i_size.to_le_bytes());
+	file.extend_from_slice(&self.i_atime.to_le_bytes());
+	file.extend_from_slice(&self.i_ctime.to_le_bytes());
+	file.extend_from_slice(&self.i_mtime.to_le_bytes());
+	file.extend_from_slice(&self.i_dtime.to_le_bytes());
+	file.extend_from_slice(&self.i_gid.to_le_bytes());
+	file.extend_from_slice(&self.i_links_count.to_le_bytes());
+	file.extend_from_slice(&self.i_blocks.to_le_bytes());
+	file.extend_from_slice(&self.i_flags.to_le_bytes());
+	file.extend_from_slice(&self.i_osd1.to_le_bytes());
 	for x in &self.i_block {
-	    file.write(&x.to_le_bytes())?;
+	    file.extend_from_slice(&x.to_le_bytes());
+	}
+	file.extend_from_slice(&self.i_generation.to_le_bytes());
+	file.extend_from_slice(&self.i_file_acl.to_le_bytes());
+	file.extend_from_slice(&self.i_dir_acl.to_le_bytes());
+	file.extend_from_slice(&self.i_faddr.to_le_bytes());
+	file.extend_from_slice(&self.l_i_frag.to_le_bytes());
+	file.extend_from_slice(&self.l_i_fsize.to_le_bytes());
+	file.extend_from_slice(&self.reserved.to_le_bytes());
+	file.extend_from_slice(&self.l_i_uid_high.to_le_bytes());
+	file.extend_from_slice(&self.l_i_gid_high.to_le_bytes());
+	file.extend_from_slice(&[0; INODE_PADDING]);
+	if inode_size > EXT2_GOOD_OLD_INODE_SIZE {
+	    file.extend_from_slice(&EXT2_INODE_EXTRA_SIZE.to_le_bytes());
+	    file.extend_from_slice(&self.i_checksum_hi.to_le_bytes());
+	    file.extend_from_slice(&self.i_ctime_extra.to_le_bytes());
+	    file.extend_from_slice(&self.i_mtime_extra.to_le_bytes());
+	    file.extend_from_slice(&self.i_atime_extra.to_le_bytes());
+	    file.extend_from_slice(&self.i_crtime.to_le_bytes());
+	    file.extend_from_slice(&self.i_crtime_extra.to_le_bytes());
+	    file.extend_from_slice(&self.i_version_hi.to_le_bytes());
+	    file.extend_from_slice(&self.i_projid.to_le_bytes());
+	    file.resize(inode_size as usize, 0);
+	}
+	dev.write_at(offset, &file).map_err(|e| Ext2Error::io(format!("writing inode at {}", offset), e))
+    }
+    // Regular files keep the high half of their size in i_dir_acl
+    pub fn size(&self) -> u64 {
+	if self.i_mode & EXT2_S_IFMT == EXT2_S_IFREG {
+	    self.i_size as u64 | (self.i_dir_acl as u64) << 32
+	} else {
+	    self.i_size as u64
 	}
-	file.write(&self.i_generation.to_le_bytes())?;
-	file.write(&self.i_file_acl.to_le_bytes())?;
-	file.write(&self.i_dir_acl.to_le_bytes())?;
-	file.write(&self.i_faddr.to_le_bytes())?;
-	file.write(&self.l_i_frag.to_le_bytes())?;
-	file.write(&self.l_i_fsize.to_le_bytes())?;
-	file.write(&self.reserved.to_le_bytes())?;
-	file.write(&self.l_i_uid_high.to_le_bytes())?;
-	file.write(&self.l_i_gid_high.to_le_bytes())?;
-	file.seek(std::io::SeekFrom::Current(INODE_PADDING))?;
-	Ok(())
     }
+
+    pub fn set_size(&mut self, size : u64) {
+	self.i_size = size as u32;
+	if self.i_mode & EXT2_S_IFMT == EXT2_S_IFREG {
+	    self.i_dir_acl = (size >> 32) as u32;
+	}
+    }
+
+    pub fn is_dir(&self) -> bool {
+	self.i_mode & EXT2_S_IFMT == EXT2_S_IFDIR
+    }
+
     pub fn new(dir : bool) -> Self {
 	let mut ret : Inode = Default::default();
 	if dir {
 	    ret.i_mode = 0x41fd;
 	    ret.i_links_count = 2;
 	} else {
-	    ret.i_mode = 0x1b5;
+	    ret.i_mode = EXT2_S_IFREG | 0x1b5;
 	    ret.i_links_count = 1;
 	}
 	ret
     }
 }
-
-impl Default for Inode {
-    fn default() -> Inode {
-	Inode {
-	    i_mode: 0,
-	    i_uid: 0,
-	    i_size: 0,
-	    i_atime: 0,
-	    i_ctime: 0,
-	    i_mtime: 0,
-	    i_dtime: 0,
-	    i_gid: 0,
-	    i_links_count: 0,
-	    i_blocks: 0,
-	    i_flags: 0,
-	    i_osd1: 0,
-	    i_block: [0; 15],
-	    i_generation: 0,
-	    i_file_acl: 0,
-	    i_dir_acl: 0,
-	    i_faddr: 0,
-	    l_i_frag: 0,
-	    l_i_fsize: 0,
-	    reserved: 0,
-	    l_i_uid_high: 0,
-	    l_i_gid_high: 0
-	}
-    }
-}
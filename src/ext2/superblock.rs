@@ -1,12 +1,15 @@
-use std::io::prelude::*;
-use std::fs::File;
-use uuid::Uuid;
 use std::convert::TryInto;
+use uuid::Uuid;
 
-use crate::ext2::BLOCK_SIZE;
-use crate::ext2::SECTOR_SIZE;
+use crate::ext2::MIN_BLOCK_SIZE;
+use crate::ext2::bgd::BGD_SIZE;
+use crate::ext2::inode::EXT2_GOOD_OLD_INODE_SIZE;
+use crate::ext2::features::{Feature, Features};
+use crate::ext2::{read_u8, read_u16, read_u32, read_u64, read_u128, read_bytes};
+use crate::device::BlockDevice;
+use crate::error::{Ext2Error, Result};
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Superblock {
     pub s_inodes_count : u32,
     pub s_blocks_count : u32,
@@ -54,90 +57,306 @@ pub struct Superblock {
     pub s_journal_dev : u32,
     pub s_last_orphan : u32,
     // DIrectory Indexing Support
-    pub s_hash_seed : [u64; 4],
+    pub s_hash_seed : [u32; 4],
     pub s_def_hash_version : u8,
     padding : [u8; 3],
     // Other options
     pub s_default_mount_options : u32,
     pub s_first_meta_bg : u32
 }
-const SUPERBLOCK_SIZE : u64 = 1024;
+pub const SUPERBLOCK_SIZE : u64 = 1024;
 pub const SUPERBLOCK_START : u64 = 1024;
+pub const EXT2_SUPER_MAGIC : u16 = 0xef53;
+pub const EXT2_GOOD_OLD_REV : u32 = 0;
+pub const EXT2_DYNAMIC_REV : u32 = 1;
+const EXT2_GOOD_OLD_FIRST_INO : u32 = 11;
+const EXT2_MAX_LOG_BLOCK_SIZE : u32 = 6;
+const EXT2_HASH_HALF_MD4 : u8 = 1;
 
 impl Superblock {
-    pub fn write(&self, mut file : &File) -> std::io::Result<()> {
-	let start = file.seek(std::io::SeekFrom::Current(0)).ok().unwrap();
-	println!("{}", start);
-	file.write(&self.s_inodes_count.to_le_bytes())?;
-	file.write(&self.s_blocks_count.to_le_bytes())?;
-	file.write(&self.s_r_blocks_count.to_le_bytes())?;
-	file.write(&self.s_free_blocks_count.to_le_bytes())?;
-	file.write(&self.s_free_inodes_count.to_le_bytes())?;
-	file.write(&self.s_first_data_block.to_le_bytes())?;
-	file.write(&self.s_log_block_size.to_le_bytes())?;
-	file.write(&self.s_log_frag_size.to_le_bytes())?;
-	file.write(&self.s_blocks_per_group.to_le_bytes())?;
-	file.write(&self.s_frags_per_group.to_le_bytes())?;
-	file.write(&self.s_inodes_per_group.to_le_bytes())?;
-	file.write(&self.s_mtime.to_le_bytes())?;
-	file.write(&self.s_wtime.to_le_bytes())?;
-	file.write(&self.s_mnt_count.to_le_bytes())?;
-	file.write(&self.s_max_mnt_count.to_le_bytes())?;
-	file.write(&self.s_magic.to_le_bytes())?;
-	file.write(&self.s_state.to_le_bytes())?;
-	file.write(&self.s_errors.to_le_bytes())?;
-	file.write(&self.s_minor_rev_level.to_le_bytes())?;
-	file.write(&self.s_lastcheck.to_le_bytes())?;
-	file.write(&self.s_checkinterval.to_le_bytes())?;
-	file.write(&self.s_creator_os.to_le_bytes())?;
-	file.write(&self.s_rev_level.to_le_bytes())?;
-	file.write(&self.s_def_resuid.to_le_bytes())?;
-	file.write(&self.s_def_resgid.to_le_bytes())?;
-	file.write(&self.s_first_ino.to_le_bytes())?;
-	file.write(&self.s_inode_size.to_le_bytes())?;
-	file.write(&self.s_block_group_nr.to_le_bytes())?;
-	file.write(&self.s_feature_compat.to_le_bytes())?;
-	file.write(&self.s_feature_incompat.to_le_bytes())?;
-	file.write(&self.s_feature_ro_compat.to_le_bytes())?;
-	file.write(&self.s_uuid.to_le_bytes())?;
-	file.write(&self.s_volume_name.to_le_bytes())?;
+    pub fn read(dev : &mut dyn BlockDevice, offset : u64) -> Result<Self> {
+	let mut raw = vec![0; SUPERBLOCK_SIZE as usize];
+	dev.read_at(offset, &mut raw).map_err(|e| Ext2Error::io(format!("reading superblock at {}", offset), e))?;
+	let mut file = &raw[..];
+	let mut sb = Superblock {
+	    s_inodes_count: read_u32(&mut file)?,
+	    s_blocks_count: read_u32(&mut file)?,
+	    s_r_blocks_count: read_u32(&mut file)?,
+	    s_free_blocks_count: read_u32(&mut file)?,
+	    s_free_inodes_count: read_u32(&mut file)?,
+	    s_first_data_block: read_u32(&mut file)?,
+	    s_log_block_size: read_u32(&mut file)?,
+	    s_log_frag_size: read_u32(&mut file)?,
+	    s_blocks_per_group: read_u32(&mut file)?,
+	    s_frags_per_group: read_u32(&mut file)?,
+	    s_inodes_per_group: read_u32(&mut file)?,
+	    s_mtime: read_u32(&mut file)?,
+	    s_wtime: read_u32(&mut file)?,
+	    s_mnt_count: read_u16(&mut file)?,
+	    s_max_mnt_count: read_u16(&mut file)?,
+	    s_magic: read_u16(&mut file)?,
+	    s_state: read_u16(&mut file)?,
+	    s_errors: read_u16(&mut file)?,
+	    s_minor_rev_level: read_u16(&mut file)?,
+	    s_lastcheck: read_u32(&mut file)?,
+	    s_checkinterval: read_u32(&mut file)?,
+	    s_creator_os: read_u32(&mut file)?,
+	    s_rev_level: read_u32(&mut file)?,
+	    s_def_resuid: read_u16(&mut file)?,
+	    s_def_resgid: read_u16(&mut file)?,
+	    s_first_ino: read_u32(&mut file)?,
+	    s_inode_size: read_u16(&mut file)?,
+	    s_block_group_nr: read_u16(&mut file)?,
+	    s_feature_compat: read_u32(&mut file)?,
+	    s_feature_incompat: read_u32(&mut file)?,
+	    s_feature_ro_compat: read_u32(&mut file)?,
+	    s_uuid: read_u128(&mut file)?,
+	    s_volume_name: read_u128(&mut file)?,
+	    s_last_mounted: {
+		let mut x = [0; 8];
+		for y in x.iter_mut() {
+		    *y = read_u64(&mut file)?;
+		}
+		x
+	    },
+	    s_algo_bitmap: read_u32(&mut file)?,
+	    s_prealloc_blocks: read_u8(&mut file)?,
+	    s_prealloc_dir_blocks: read_u8(&mut file)?,
+	    alignment: read_u16(&mut file)?,
+	    s_journal_uuid: read_u128(&mut file)?,
+	    s_journal_inum: read_u32(&mut file)?,
+	    s_journal_dev: read_u32(&mut file)?,
+	    s_last_orphan: read_u32(&mut file)?,
+	    s_hash_seed: {
+		let mut x = [0; 4];
+		for y in x.iter_mut() {
+		    *y = read_u32(&mut file)?;
+		}
+		x
+	    },
+	    s_def_hash_version: read_u8(&mut file)?,
+	    padding: {
+		read_bytes(&mut file)?
+	    },
+	    s_default_mount_options: read_u32(&mut file)?,
+	    s_first_meta_bg: read_u32(&mut file)?
+	};
+	if sb.s_magic != EXT2_SUPER_MAGIC {
+	    return Err(Ext2Error::Corrupt(format!("Bad superblock magic {:#x}", sb.s_magic)));
+	}
+	if sb.s_rev_level > EXT2_DYNAMIC_REV {
+	    return Err(Ext2Error::Corrupt(format!("Unsupported revision level {}", sb.s_rev_level)));
+	}
+	if sb.s_log_block_size > EXT2_MAX_LOG_BLOCK_SIZE {
+	    return Err(Ext2Error::Corrupt(format!("Bad block size shift {}", sb.s_log_block_size)));
+	}
+	if sb.s_rev_level == EXT2_GOOD_OLD_REV {
+	    // The dynamic fields aren't meaningful in old revision filesystems
+	    sb.s_first_ino = EXT2_GOOD_OLD_FIRST_INO;
+	    sb.s_inode_size = EXT2_GOOD_OLD_INODE_SIZE as u16;
+	}
+	sb.validate()?;
+	Ok(sb)
+    }
+
+    // Checks the geometry fields that everything else divides by or
+    // indexes with
+    fn validate(&self) -> Result<()> {
+	let block_size = self.block_size();
+	let first_data_block = if block_size == MIN_BLOCK_SIZE { 1 } else { 0 };
+	if self.s_first_data_block != first_data_block {
+	    return Err(Ext2Error::Corrupt(format!("Bad first data block {}", self.s_first_data_block)));
+	}
+	if self.s_blocks_count <= self.s_first_data_block {
+	    return Err(Ext2Error::Corrupt(format!("Bad block count {}", self.s_blocks_count)));
+	}
+	if self.s_blocks_per_group == 0 || self.s_blocks_per_group > block_size * 8 {
+	    return Err(Ext2Error::Corrupt(format!("Bad number of blocks per group {}", self.s_blocks_per_group)));
+	}
+	if self.s_inodes_per_group == 0 || self.s_inodes_per_group > block_size * 8 {
+	    return Err(Ext2Error::Corrupt(format!("Bad number of inodes per group {}", self.s_inodes_per_group)));
+	}
+	let inode_size = self.inode_size();
+	if !inode_size.is_power_of_two() || inode_size < EXT2_GOOD_OLD_INODE_SIZE || inode_size > block_size {
+	    return Err(Ext2Error::Corrupt(format!("Bad inode size {}", inode_size)));
+	}
+	// Every inode number has to fall in a group
+	let max_inodes = self.s_inodes_per_group as u64 * self.num_groups() as u64;
+	if self.s_inodes_count == 0 || self.s_inodes_count as u64 > max_inodes {
+	    return Err(Ext2Error::Corrupt(format!("Bad inode count {}", self.s_inodes_count)));
+	}
+	if self.s_first_ino < EXT2_GOOD_OLD_FIRST_INO || self.s_first_ino > self.s_inodes_count {
+	    return Err(Ext2Error::Corrupt(format!("Bad first inode {}", self.s_first_ino)));
+	}
+	Ok(())
+    }
+
+    pub fn write(&self, dev : &mut dyn BlockDevice, offset : u64) -> Result<()> {
+	let mut file : Vec<u8> = Vec::with_capacity(SUPERBLOCK_SIZE as usize);
+	file.extend_from_slice(&self.s_inodes_count.to_le_bytes());
+	file.extend_from_slice(&self.s_blocks_count.to_le_bytes());
+	file.extend_from_slice(&self.s_r_blocks_count.to_le_bytes());
+	file.extend_from_slice(&self.s_free_blocks_count.to_le_bytes());
+	file.extend_from_slice(&self.s_free_inodes_count.to_le_bytes());
+	file.extend_from_slice(&self.s_first_data_block.to_le_bytes());
+	file.extend_from_slice(&self.s_log_block_size.to_le_bytes());
+	file.extend_from_slice(&self.s_log_frag_size.to_le_bytes());
+	file.extend_from_slice(&self.s_blocks_per_group.to_le_bytes());
+	file.extend_from_slice(&self.s_frags_per_group.to_le_bytes());
+	file.extend_from_slice(&self.s_inodes_per_group.to_le_bytes());
+	file.extend_from_slice(&self.s_mtime.to_le_bytes());
+	file.extend_from_slice(&self.s_wtime.to_le_bytes());
+	file.extend_from_slice(&self.s_mnt_count.to_le_bytes());
+	file.extend_from_slice(&self.s_max_mnt_count.to_le_bytes());
+	file.extend_from_slice(&self.s_magic.to_le_bytes());
+	file.extend_from_slice(&self.s_state.to_le_bytes());
+	file.extend_from_slice(&self.s_errors.to_le_bytes());
+	file.extend_from_slice(&self.s_minor_rev_level.to_le_bytes());
+	file.extend_from_slice(&self.s_lastcheck.to_le_bytes());
+	file.extend_from_slice(&self.s_checkinterval.to_le_bytes());
+	file.extend_from_slice(&self.s_creator_os.to_le_bytes());
+	file.extend_from_slice(&self.s_rev_level.to_le_bytes());
+	file.extend_from_slice(&self.s_def_resuid.to_le_bytes());
+	file.extend_from_slice(&self.s_def_resgid.to_le_bytes());
+	file.extend_from_slice(&self.s_first_ino.to_le_bytes());
+	file.extend_from_slice(&self.s_inode_size.to_le_bytes());
+	file.extend_from_slice(&self.s_block_group_nr.to_le_bytes());
+	file.extend_from_slice(&self.s_feature_compat.to_le_bytes());
+	file.extend_from_slice(&self.s_feature_incompat.to_le_bytes());
+	file.extend_from_slice(&self.s_feature_ro_compat.to_le_bytes());
+	file.extend_from_slice(&self.s_uuid.to_le_bytes());
+	file.extend_from_slice(&self.s_volume_name.to_le_bytes());
 	for x in &self.s_last_mounted {
-	    file.write(&x.to_le_bytes())?;
-	}
-	file.write(&self.s_algo_bitmap.to_le_bytes())?;
-	file.write(&self.s_prealloc_blocks.to_le_bytes())?;
-	file.write(&self.s_prealloc_dir_blocks.to_le_bytes())?;
-	file.write(&self.alignment.to_le_bytes())?;
-	file.write(&self.s_journal_uuid.to_le_bytes())?;
-	file.write(&self.s_journal_inum.to_le_bytes())?;
-	file.write(&self.s_journal_dev.to_le_bytes())?;
-	file.write(&self.s_last_orphan.to_le_bytes())?;
+	    file.extend_from_slice(&x.to_le_bytes());
+	}
+	file.extend_from_slice(&self.s_algo_bitmap.to_le_bytes());
+	file.extend_from_slice(&self.s_prealloc_blocks.to_le_bytes());
+	file.extend_from_slice(&self.s_prealloc_dir_blocks.to_le_bytes());
+	file.extend_from_slice(&self.alignment.to_le_bytes());
+	file.extend_from_slice(&self.s_journal_uuid.to_le_bytes());
+	file.extend_from_slice(&self.s_journal_inum.to_le_bytes());
+	file.extend_from_slice(&self.s_journal_dev.to_le_bytes());
+	file.extend_from_slice(&self.s_last_orphan.to_le_bytes());
 	for x in &self.s_hash_seed {
-	    file.write(&x.to_le_bytes())?;
-	}
-	file.write(&self.s_hash_seed[1].to_le_bytes())?;
-	file.write(&self.s_hash_seed[2].to_le_bytes())?;
-	file.write(&self.s_hash_seed[3].to_le_bytes())?;
-	file.write(&self.s_def_hash_version.to_le_bytes())?;
-	file.write(&self.padding)?;
-	file.write(&self.s_default_mount_options.to_le_bytes())?;
-	file.write(&self.s_first_meta_bg.to_le_bytes())?;
-	let current = file.seek(std::io::SeekFrom::Current(0)).ok().unwrap();
-	file.seek(std::io::SeekFrom::Current((SUPERBLOCK_SIZE - (current - start)).try_into().unwrap()))?;
-	println!("{}", start);
-	Ok(())
+	    file.extend_from_slice(&x.to_le_bytes());
+	}
+	file.extend_from_slice(&self.s_def_hash_version.to_le_bytes());
+	file.extend_from_slice(&self.padding);
+	file.extend_from_slice(&self.s_default_mount_options.to_le_bytes());
+	file.extend_from_slice(&self.s_first_meta_bg.to_le_bytes());
+	file.resize(SUPERBLOCK_SIZE as usize, 0);
+	dev.write_at(offset, &file).map_err(|e| Ext2Error::io(format!("writing superblock at {}", offset), e))
     }
 
-    pub fn new(num_sectors : u32, num_reserved_sectors : u32) -> Self {
+    pub fn new(bytes : u64, reserved_bytes : u64, block_size : u32) -> Result<Self> {
 	let mut sb : Superblock = Default::default();
-	sb.s_blocks_count = num_sectors * SECTOR_SIZE / BLOCK_SIZE;
-	sb.s_r_blocks_count = (num_sectors - num_reserved_sectors) * SECTOR_SIZE / (BLOCK_SIZE * 20);
-	sb.s_free_blocks_count = sb.s_blocks_count - 2;
-	let num_groups = (sb.s_blocks_count + sb.s_blocks_per_group - 1) / sb.s_blocks_per_group;
-	sb.s_inodes_count = sb.s_inodes_per_group * num_groups;
-	sb.s_free_inodes_count = sb.s_inodes_count;
+	sb.s_log_block_size = (block_size / MIN_BLOCK_SIZE).trailing_zeros();
+	sb.s_log_frag_size = sb.s_log_block_size;
+	// The superblock always starts 1024 bytes in, which is block 0 for
+	// anything but 1K blocks.
+	sb.s_first_data_block = if block_size == MIN_BLOCK_SIZE { 1 } else { 0 };
+	sb.s_blocks_count = match (bytes / block_size as u64).try_into() {
+	    Ok(blocks) => blocks,
+	    Err(_) => return Err(Ext2Error::Overflow(format!("{} bytes is too many {} byte blocks", bytes, block_size)))
+	};
+	// 5% of the non-reserved space, which is always less than the block count
+	sb.s_r_blocks_count = (bytes.saturating_sub(reserved_bytes) / (block_size as u64 * 20)) as u32;
+	sb.s_free_blocks_count = sb.s_blocks_count - sb.s_first_data_block;
+	sb.set_inodes_per_group(sb.s_inodes_per_group)?;
 	sb.s_uuid = Uuid::new_v4().as_u128();
-	sb
+	Ok(sb)
+    }
+
+    pub fn set_blocks_count(&mut self, blocks_count : u32) -> Result<()> {
+	self.s_blocks_count = blocks_count;
+	self.s_free_blocks_count = self.s_blocks_count - self.s_first_data_block;
+	self.set_inodes_per_group(self.s_inodes_per_group)
+    }
+
+    pub fn set_blocks_per_group(&mut self, blocks_per_group : u32) -> Result<()> {
+	self.s_blocks_per_group = blocks_per_group;
+	self.s_frags_per_group = blocks_per_group;
+	self.set_inodes_per_group(self.s_inodes_per_group)
+    }
+
+    pub fn set_inodes_per_group(&mut self, inodes_per_group : u32) -> Result<()> {
+	// Fill out the whole inode table blocks
+	self.s_inodes_per_group = inodes_per_group.next_multiple_of(self.block_size() / self.inode_size());
+	self.s_inodes_count = match self.s_inodes_per_group.checked_mul(self.num_groups()) {
+	    Some(count) => count,
+	    None => return Err(Ext2Error::Overflow(format!("Too many inodes: {} per group in {} groups", self.s_inodes_per_group, self.num_groups())))
+	};
+	self.s_free_inodes_count = self.s_inodes_count;
+	Ok(())
+    }
+
+    // Other inode sizes need a revision 1 filesystem
+    pub fn set_inode_size(&mut self, inode_size : u16) -> Result<()> {
+	self.s_rev_level = EXT2_DYNAMIC_REV;
+	self.s_inode_size = inode_size;
+	self.set_inodes_per_group(self.s_inodes_per_group)
+    }
+
+    pub fn itable_blocks(&self) -> u32 {
+	self.s_inodes_per_group * self.inode_size() / self.block_size()
+    }
+
+    pub fn block_size(&self) -> u32 {
+	MIN_BLOCK_SIZE << self.s_log_block_size
+    }
+
+    pub fn inode_size(&self) -> u32 {
+	self.s_inode_size as u32
+    }
+
+    pub fn num_groups(&self) -> u32 {
+	(self.s_blocks_count - self.s_first_data_block).div_ceil(self.s_blocks_per_group)
+    }
+
+    pub fn features(&self) -> Features {
+	Features {
+	    compat: self.s_feature_compat,
+	    incompat: self.s_feature_incompat,
+	    ro_compat: self.s_feature_ro_compat
+	}
+    }
+
+    // Features need a revision 1 filesystem
+    pub fn set_features(&mut self, features : Features) {
+	if !features.is_empty() {
+	    self.s_rev_level = EXT2_DYNAMIC_REV;
+	}
+	self.s_feature_compat = features.compat;
+	self.s_feature_incompat = features.incompat;
+	self.s_feature_ro_compat = features.ro_compat;
+	// Indexed directories hash names with a per-filesystem seed
+	if features.contains(Feature::DirIndex) {
+	    let seed = Uuid::new_v4().as_u128();
+	    self.s_hash_seed = [seed as u32, (seed >> 32) as u32, (seed >> 64) as u32, (seed >> 96) as u32];
+	    self.s_def_hash_version = EXT2_HASH_HALF_MD4;
+	}
+    }
+
+    // Only groups 0, 1 and powers of 3, 5 and 7 carry backups with sparse_super
+    pub fn has_super(&self, group : u32) -> bool {
+	if !self.features().contains(Feature::SparseSuper) || group <= 1 {
+	    return true;
+	}
+	[3, 5, 7].iter().any(|&base| {
+	    let mut power : u32 = base;
+	    while power < group {
+		match power.checked_mul(base) {
+		    Some(next) => power = next,
+		    None => return false
+		}
+	    }
+	    power == group
+	})
+    }
+
+    // Blocks taken by the group descriptor table, after each superblock
+    pub fn gdt_blocks(&self) -> u32 {
+	(self.num_groups() * BGD_SIZE).div_ceil(self.block_size())
     }
 }
 
@@ -155,15 +374,16 @@ impl Default for Superblock {
 	    s_blocks_per_group: 512,
 	    s_frags_per_group: 512,
 	    s_inodes_per_group: 24, // If the first inode table starts at block 6
-	    			    // and we only have 8 blocks, we can only have
-	    			    // 3 blocks of inode table. At 8 inodes per block,
-	    			    // that's 24 inodes per group. This could be increased 
-	    			    // by moving the inode table after the reserved sectors.
+				    // and we only have 8 blocks, we can only have
+				    // 3 blocks of inode table. At 8 inodes per block,
+				    // that's 24 inodes per group. Layout::ReservedFirst
+				    // moves the inode table after the reserved sectors
+				    // to make room for more.
 	    s_mtime: 0,
 	    s_wtime: 0,
 	    s_mnt_count: 0,
 	    s_max_mnt_count: 1024,
-	    s_magic: 0xef53,
+	    s_magic: EXT2_SUPER_MAGIC,
 	    s_state: 1,
 	    s_errors: 1,
 	    s_minor_rev_level: 0,
@@ -203,3 +423,23 @@ impl Default for Superblock {
 	}
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sparse_super_backups() {
+	let mut sb = Superblock::default();
+	let groups = [0, 1, 2, 3, 5, 6, 7, 9, 10, 15, 21, 25, 27, 49, 125, 343, u32::MAX];
+	for group in groups {
+	    assert!(sb.has_super(group), "group {} without sparse_super", group);
+	}
+
+	let mut features = Features::default();
+	features.insert(Feature::SparseSuper);
+	sb.set_features(features);
+	let with_backup : Vec<u32> = groups.iter().copied().filter(|&group| sb.has_super(group)).collect();
+	assert_eq!(with_backup, [0, 1, 3, 5, 7, 9, 25, 27, 49, 125, 343]);
+    }
+}
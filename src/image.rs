@@ -0,0 +1,756 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use crate::ext2::bgd::BGD_SIZE;
+use crate::ext2::superblock::Superblock;
+use crate::ext2::superblock::SUPERBLOCK_START;
+use crate::ext2::bgd::BGD;
+use crate::ext2::bitmap::Bitmap;
+use crate::ext2::SECTOR_SIZE;
+use crate::ext2::inode::{Inode, EXT2_N_BLOCKS, EXT2_NDIR_BLOCKS};
+use crate::ext2::inode::{EXT2_IND_BLOCK, EXT2_DIND_BLOCK, EXT2_TIND_BLOCK};
+use crate::ext2::directory::{Directory, file_type, EXT2_FT_UNKNOWN};
+use crate::ext2::features::Feature;
+use crate::device::BlockDevice;
+use crate::error::{Ext2Error, Result};
+
+pub const ROOT_INO : u32 = 2;
+
+#[derive(Debug)]
+pub struct Ext2Image {
+    pub sb : Superblock,
+    pub bgds : Vec<BGD>,
+    pub block_bmaps : Vec<Bitmap>,
+    pub inode_bmaps : Vec<Bitmap>,
+    // Inodes that have been filled in, by inode number
+    pub inodes : BTreeMap<u32, Inode>,
+    // Directory contents, by inode number. Each is written to the blocks its
+    // inode points at.
+    pub dirs : BTreeMap<u32, Directory>,
+    // Contents of data blocks, by block number
+    pub data : BTreeMap<u32, Vec<u8>>,
+    // Runs of data blocks copied from host files when the image is written,
+    // as (first block, number of blocks, file, byte offset in the file)
+    pub file_data : Vec<(u32, u32, PathBuf, u64)>,
+    // Reserved block extents as (first block, number of blocks)
+    pub reserved : Vec<(u32, u32)>,
+    // Bytes written to the device as they are, by byte offset. These go
+    // outside the filesystem, or into reserved blocks.
+    pub raw : BTreeMap<u64, Vec<u8>>,
+    // Sectors kept clear of the filesystem, as (first sector, number of sectors)
+    pub protected : Vec<(u64, u64)>,
+    // Where the reserved payload went, as (first sector, number of sectors)
+    pub payload : Vec<(u64, u64)>
+}
+
+impl Ext2Image {
+    pub fn new(sb : Superblock) -> Result<Self> {
+	let num_groups = sb.num_groups();
+	let bgds = (0 .. num_groups).map(|i| BGD::new(&sb, i)).collect::<Result<Vec<BGD>>>()?;
+	let mut block_bmaps = Vec::new();
+	let mut inode_bmaps = Vec::new();
+	for bgd in &bgds {
+	    let mut block_bmap = Bitmap::new(sb.s_blocks_per_group);
+	    // The tail of the last group's bitmap doesn't map to real blocks
+	    for i in bgd.num_blocks(&sb) .. sb.s_blocks_per_group {
+		block_bmap.set(i, true);
+	    }
+	    block_bmaps.push(block_bmap);
+	    inode_bmaps.push(Bitmap::new(sb.s_inodes_per_group));
+	}
+	Ok(Ext2Image {
+	    sb,
+	    bgds,
+	    block_bmaps,
+	    inode_bmaps,
+	    inodes: BTreeMap::new(),
+	    dirs: BTreeMap::new(),
+	    data: BTreeMap::new(),
+	    file_data: Vec::new(),
+	    reserved: Vec::new(),
+	    raw: BTreeMap::new(),
+	    protected: Vec::new(),
+	    payload: Vec::new()
+	})
+    }
+
+    pub fn read(dev : &mut dyn BlockDevice) -> Result<Self> {
+	let sb = Superblock::read(dev, SUPERBLOCK_START)?;
+	let block_size = sb.block_size();
+	let gdt = (sb.s_first_data_block as u64 + 1) * block_size as u64;
+	let mut bgds = Vec::new();
+	let mut block_bmaps = Vec::new();
+	let mut inode_bmaps = Vec::new();
+	for i in 0 .. sb.num_groups() {
+	    let bgd = BGD::read(dev, gdt + (i as u64 * BGD_SIZE as u64), i)?;
+	    let itable_end = bgd.bg_inode_table as u64 + sb.itable_blocks() as u64;
+	    if bgd.bg_block_bitmap >= sb.s_blocks_count || bgd.bg_inode_bitmap >= sb.s_blocks_count || itable_end > sb.s_blocks_count as u64 {
+		return Err(Ext2Error::Corrupt(format!("Group {} metadata is outside the filesystem", i)));
+	    }
+	    block_bmaps.push(Bitmap::read(dev, bgd.bg_block_bitmap, sb.s_blocks_per_group, block_size)?);
+	    inode_bmaps.push(Bitmap::read(dev, bgd.bg_inode_bitmap, sb.s_inodes_per_group, block_size)?);
+	    bgds.push(bgd);
+	}
+	let mut image = Ext2Image {
+	    sb,
+	    bgds,
+	    block_bmaps,
+	    inode_bmaps,
+	    inodes: BTreeMap::new(),
+	    dirs: BTreeMap::new(),
+	    data: BTreeMap::new(),
+	    file_data: Vec::new(),
+	    reserved: Vec::new(),
+	    raw: BTreeMap::new(),
+	    protected: Vec::new(),
+	    payload: Vec::new()
+	};
+	// The reserved inodes, and every other one the bitmaps say is in use,
+	// so that writing the image back keeps them
+	let ipg = image.sb.s_inodes_per_group;
+	let used = (image.sb.s_first_ino ..= image.sb.s_inodes_count).filter(|&ino| image.inode_bmaps[((ino - 1) / ipg) as usize].get((ino - 1) % ipg));
+	for ino in (1 .. image.sb.s_first_ino).chain(used.collect::<Vec<u32>>()) {
+	    let inode = image.read_inode(dev, ino)?;
+	    image.inodes.insert(ino, inode);
+	}
+	let dirs : Vec<(u32, Inode)> = image.inodes.iter().filter(|(_, inode)| inode.is_dir()).map(|(&ino, inode)| (ino, inode.clone())).collect();
+	for (ino, inode) in dirs {
+	    let blocks = image.load_blocks(dev, &inode)?;
+	    let dir = Directory::read(dev, &blocks, block_size)?;
+	    image.dirs.insert(ino, dir);
+	}
+	if !image.dirs.contains_key(&ROOT_INO) {
+	    return Err(Ext2Error::Corrupt(String::from("Root inode is not a directory")));
+	}
+	Ok(image)
+    }
+
+    pub fn inode_offset(&self, ino : u32) -> u64 {
+	let group = ((ino - 1) / self.sb.s_inodes_per_group) as usize;
+	let index = (ino - 1) % self.sb.s_inodes_per_group;
+	self.bgds[group].bg_inode_table as u64 * self.sb.block_size() as u64 + index as u64 * self.sb.inode_size() as u64
+    }
+
+    pub fn read_inode(&self, dev : &mut dyn BlockDevice, ino : u32) -> Result<Inode> {
+	if ino == 0 || ino > self.sb.s_inodes_count {
+	    return Err(Ext2Error::InvalidInput(format!("Inode {} out of range", ino)));
+	}
+	Inode::read(dev, self.inode_offset(ino), self.sb.inode_size())
+    }
+
+    pub fn is_block_used(&self, block : u32) -> bool {
+	let rel = block - self.sb.s_first_data_block;
+	let group = (rel / self.sb.s_blocks_per_group) as usize;
+	self.block_bmaps[group].get(rel % self.sb.s_blocks_per_group)
+    }
+
+    pub fn set_block_used(&mut self, block : u32) {
+	let rel = block - self.sb.s_first_data_block;
+	let group = (rel / self.sb.s_blocks_per_group) as usize;
+	let bmap = &mut self.block_bmaps[group];
+	if !bmap.get(rel % self.sb.s_blocks_per_group) {
+	    bmap.set(rel % self.sb.s_blocks_per_group, true);
+	    self.bgds[group].bg_free_blocks_count -= 1;
+	    self.sb.s_free_blocks_count -= 1;
+	}
+    }
+
+    pub fn alloc_block(&mut self) -> Option<u32> {
+	for group in 0 .. self.bgds.len() {
+	    if self.bgds[group].bg_free_blocks_count == 0 {
+		continue;
+	    }
+	    if let Some(i) = self.block_bmaps[group].first_free() {
+		let block = self.bgds[group].get_start(&self.sb) + i;
+		self.set_block_used(block);
+		return Some(block);
+	    }
+	}
+	None
+    }
+
+    pub fn free_blocks_from(&self, start : u32) -> u32 {
+	(start .. self.sb.s_blocks_count).filter(|&b| !self.is_block_used(b)).count() as u32
+    }
+
+    // Marks count blocks from start onwards as used, stepping over blocks
+    // that are already in use, such as the metadata at the start of each group.
+    // Returns the extents that were reserved.
+    pub fn reserve(&mut self, start : u32, count : u32) -> Result<Vec<(u32, u32)>> {
+	let mut extents : Vec<(u32, u32)> = Vec::new();
+	let mut block = start;
+	let mut left = count;
+	while left > 0 {
+	    if block >= self.sb.s_blocks_count {
+		return Err(Ext2Error::OutOfSpace(format!("Only {} of {} reserved blocks fit", count - left, count)));
+	    }
+	    if !self.is_block_used(block) {
+		self.set_block_used(block);
+		match extents.last_mut() {
+		    Some((first, len)) if *first + *len == block => *len += 1,
+		    _ => extents.push((block, 1))
+		}
+		left -= 1;
+	    }
+	    block += 1;
+	}
+	self.reserved.extend_from_slice(&extents);
+	Ok(extents)
+    }
+
+    fn is_reserved(&self, block : u32) -> bool {
+	self.reserved.iter().any(|&(first, len)| block >= first && block < first + len)
+    }
+
+    // Marks count blocks from start onwards as used. The region is at a fixed
+    // place on the device, so they all have to be free, or already reserved
+    // by another region that shares a block with this one. Blocks outside the
+    // filesystem are never used by it and are left alone.
+    pub fn reserve_at(&mut self, start : u64, count : u64) -> Result<()> {
+	let last = std::cmp::min(start + count, self.sb.s_blocks_count as u64);
+	let first = std::cmp::max(start, self.sb.s_first_data_block as u64).min(last);
+	let (first, last) = (first as u32, last as u32);
+	if let Some(block) = (first .. last).find(|&b| self.is_block_used(b) && !self.is_reserved(b)) {
+	    return Err(Ext2Error::InvalidGeometry(format!("Reserved block {} is already in use", block)));
+	}
+	let mut extents : Vec<(u32, u32)> = Vec::new();
+	for block in first .. last {
+	    if self.is_block_used(block) {
+		continue;
+	    }
+	    self.set_block_used(block);
+	    match extents.last_mut() {
+		Some((first, len)) if *first + *len == block => *len += 1,
+		_ => extents.push((block, 1))
+	    }
+	}
+	self.reserved.extend_from_slice(&extents);
+	Ok(())
+    }
+
+    pub fn alloc_blocks(&mut self, count : u32) -> Result<Vec<u32>> {
+	let mut blocks = Vec::with_capacity(count as usize);
+	for _ in 0 .. count {
+	    match self.alloc_block() {
+		Some(block) => blocks.push(block),
+		None => return Err(Ext2Error::OutOfSpace(String::from("Out of free blocks")))
+	    }
+	}
+	Ok(blocks)
+    }
+
+    pub fn alloc_inode(&mut self, dir : bool) -> Result<u32> {
+	let ipg = self.sb.s_inodes_per_group;
+	for group in 0 .. self.bgds.len() {
+	    if self.bgds[group].bg_free_inodes_count == 0 {
+		continue;
+	    }
+	    while let Some(i) = self.inode_bmaps[group].first_free() {
+		if i >= ipg {
+		    break;
+		}
+		let ino = group as u32 * ipg + i + 1;
+		self.set_inode_used(ino);
+		// The reserved inodes are never handed out
+		if ino < self.sb.s_first_ino {
+		    continue;
+		}
+		if dir {
+		    self.bgds[group].bg_used_dirs_count += 1;
+		}
+		return Ok(ino);
+	    }
+	}
+	Err(Ext2Error::OutOfSpace(String::from("Out of free inodes")))
+    }
+
+    // Adds a directory entry for an existing inode
+    pub fn link(&mut self, parent : u32, name : &str, ino : u32) -> Result<()> {
+	let file_type = match self.inodes.get(&ino) {
+	    Some(inode) if self.sb.features().contains(Feature::Filetype) => file_type(inode.i_mode),
+	    _ => EXT2_FT_UNKNOWN
+	};
+	let size = match self.dirs.get_mut(&parent) {
+	    Some(dir) => {
+		dir.add(ino, name, file_type)?;
+		dir.len()
+	    },
+	    None => return Err(Ext2Error::InvalidInput(format!("Inode {} is not a directory", parent)))
+	};
+	// Map another block if the directory spilled into one
+	let mut dir_inode = self.inodes[&parent].clone();
+	while dir_inode.i_size < size {
+	    let logical = dir_inode.i_size / self.sb.block_size();
+	    self.append_block(&mut dir_inode, logical)?;
+	    dir_inode.i_size += self.sb.block_size();
+	}
+	self.inodes.insert(parent, dir_inode);
+	if let Some(inode) = self.inodes.get_mut(&ino) {
+	    inode.i_links_count += 1;
+	}
+	Ok(())
+    }
+
+    pub fn mkdir(&mut self, parent : u32, name : &str, mut inode : Inode) -> Result<u32> {
+	let block_size = self.sb.block_size();
+	let ino = self.alloc_inode(true)?;
+	let dir = Directory::new(ino, parent, block_size, self.sb.features().contains(Feature::Filetype));
+	inode.i_block = [0; EXT2_N_BLOCKS];
+	inode.i_blocks = 0;
+	self.append_block(&mut inode, 0)?;
+	inode.i_size = dir.len();
+	// Accounts for "."; the entry in the parent adds the other link
+	inode.i_links_count = 1;
+	self.inodes.insert(ino, inode);
+	self.dirs.insert(ino, dir);
+	self.link(parent, name, ino)?;
+	if let Some(parent) = self.inodes.get_mut(&parent) {
+	    parent.i_links_count += 1;
+	}
+	Ok(ino)
+    }
+
+    pub fn write_file(&mut self, parent : u32, name : &str, mut inode : Inode, contents : &[u8]) -> Result<u32> {
+	let ino = self.alloc_inode(false)?;
+	self.set_contents(&mut inode, contents)?;
+	inode.i_links_count = 0;
+	self.inodes.insert(ino, inode);
+	self.link(parent, name, ino)?;
+	Ok(ino)
+    }
+
+    // Like write_file, but the contents are only read from the host file
+    // when the image is written
+    pub fn copy_file(&mut self, parent : u32, name : &str, mut inode : Inode, path : &Path, size : u64) -> Result<u32> {
+	let ino = self.alloc_inode(false)?;
+	let blocks = self.alloc_contents(&mut inode, size)?;
+	let mut offset = 0;
+	for (i, &block) in blocks.iter().enumerate() {
+	    match self.file_data.last_mut() {
+		Some((first, len, _, _)) if i > 0 && *first + *len == block => *len += 1,
+		_ => self.file_data.push((block, 1, path.to_path_buf(), offset))
+	    }
+	    offset += self.sb.block_size() as u64;
+	}
+	inode.i_links_count = 0;
+	self.inodes.insert(ino, inode);
+	self.link(parent, name, ino)?;
+	Ok(ino)
+    }
+
+    pub fn symlink(&mut self, parent : u32, name : &str, mut inode : Inode, target : &[u8]) -> Result<u32> {
+	let ino = self.alloc_inode(false)?;
+	// Short targets are stored in i_block itself
+	if target.len() < EXT2_N_BLOCKS * 4 {
+	    let mut raw = [0; EXT2_N_BLOCKS * 4];
+	    raw[.. target.len()].copy_from_slice(target);
+	    for (i, x) in inode.i_block.iter_mut().enumerate() {
+		*x = u32::from_le_bytes([raw[i * 4], raw[i * 4 + 1], raw[i * 4 + 2], raw[i * 4 + 3]]);
+	    }
+	    inode.i_size = target.len() as u32;
+	    inode.i_blocks = 0;
+	} else {
+	    self.set_contents(&mut inode, target)?;
+	}
+	inode.i_links_count = 0;
+	self.inodes.insert(ino, inode);
+	self.link(parent, name, ino)?;
+	Ok(ino)
+    }
+
+    // Device nodes, FIFOs and sockets. The device number is ignored for the latter two.
+    pub fn mknod(&mut self, parent : u32, name : &str, mut inode : Inode, major : u32, minor : u32) -> Result<u32> {
+	let ino = self.alloc_inode(false)?;
+	if major < 256 && minor < 256 {
+	    inode.i_block[0] = (major << 8) | minor;
+	} else {
+	    inode.i_block[1] = (minor & 0xff) | (major << 8) | ((minor & !0xff) << 12);
+	}
+	inode.i_links_count = 0;
+	self.inodes.insert(ino, inode);
+	self.link(parent, name, ino)?;
+	Ok(ino)
+    }
+
+    // Files of 2GiB or more need large_file
+    pub fn max_file_size(&self) -> u64 {
+	if self.sb.features().contains(Feature::LargeFile) {
+	    u64::MAX
+	} else {
+	    i32::MAX as u64
+	}
+    }
+
+    // Allocates and maps the data blocks for size bytes, and returns them
+    // in logical order
+    fn alloc_contents(&mut self, inode : &mut Inode, size : u64) -> Result<Vec<u32>> {
+	if size > self.max_file_size() {
+	    return Err(Ext2Error::Overflow(format!("{} bytes is too large for a file", size)));
+	}
+	inode.i_block = [0; EXT2_N_BLOCKS];
+	inode.i_blocks = 0;
+	let num_blocks = size.div_ceil(self.sb.block_size() as u64);
+	if num_blocks > u32::MAX as u64 {
+	    return Err(Ext2Error::Overflow(format!("{} bytes is too large for a file", size)));
+	}
+	let blocks = (0 .. num_blocks as u32).map(|i| self.append_block(inode, i)).collect::<Result<Vec<u32>>>()?;
+	inode.set_size(size);
+	Ok(blocks)
+    }
+
+    // Stores contents in newly allocated data blocks
+    pub fn set_contents(&mut self, inode : &mut Inode, contents : &[u8]) -> Result<()> {
+	let blocks = self.alloc_contents(inode, contents.len() as u64)?;
+	for (&block, chunk) in blocks.iter().zip(contents.chunks(self.sb.block_size() as usize)) {
+	    self.data.insert(block, chunk.to_vec());
+	}
+	Ok(())
+    }
+
+    // Splits a logical block number into the i_block slot that maps it and
+    // the index at each level of indirection below that
+    fn block_path(&self, logical : u32) -> Result<(usize, Vec<u32>)> {
+	let ptrs = (self.sb.block_size() / 4) as u64;
+	let mut rel = logical as u64;
+	if rel < EXT2_NDIR_BLOCKS as u64 {
+	    return Ok((rel as usize, Vec::new()));
+	}
+	rel -= EXT2_NDIR_BLOCKS as u64;
+	let mut span = ptrs;
+	for (depth, slot) in [(1, EXT2_IND_BLOCK), (2, EXT2_DIND_BLOCK), (3, EXT2_TIND_BLOCK)] {
+	    if rel < span {
+		let mut path = Vec::new();
+		for level in (0 .. depth).rev() {
+		    path.push((rel / ptrs.pow(level) % ptrs) as u32);
+		}
+		return Ok((slot, path));
+	    }
+	    rel -= span;
+	    span *= ptrs;
+	}
+	Err(Ext2Error::Overflow(format!("Block {} is past the largest possible file", logical)))
+    }
+
+    fn indirect_ptr(&self, indirect : u32, index : u32) -> Result<u32> {
+	let i = index as usize * 4;
+	match self.data.get(&indirect) {
+	    Some(b) if b.len() >= i + 4 => Ok(u32::from_le_bytes([b[i], b[i + 1], b[i + 2], b[i + 3]])),
+	    Some(_) => Ok(0),
+	    None => Err(Ext2Error::Corrupt(format!("Indirect block {} is not loaded", indirect)))
+	}
+    }
+
+    fn set_indirect_ptr(&mut self, indirect : u32, index : u32, block : u32) {
+	let block_size = self.sb.block_size() as usize;
+	let contents = self.data.entry(indirect).or_default();
+	contents.resize(block_size, 0);
+	contents[index as usize * 4 .. index as usize * 4 + 4].copy_from_slice(&block.to_le_bytes());
+    }
+
+    // Allocates a data block and maps it at the given logical block of the
+    // inode, allocating any indirect blocks along the way
+    pub fn append_block(&mut self, inode : &mut Inode, logical : u32) -> Result<u32> {
+	self.map_block(inode, logical, None)
+    }
+
+    // Maps a block that is already in use, or a newly allocated one if there
+    // is none, at the given logical block of the inode
+    pub fn map_block(&mut self, inode : &mut Inode, logical : u32, block : Option<u32>) -> Result<u32> {
+	let sectors = self.sb.block_size() / SECTOR_SIZE;
+	let (slot, path) = self.block_path(logical)?;
+	if path.is_empty() {
+	    let block = match block {
+		Some(block) => block,
+		None => self.alloc_blocks(1)?[0]
+	    };
+	    inode.i_block[slot] = block;
+	    inode.i_blocks += sectors;
+	    return Ok(block);
+	}
+	if inode.i_block[slot] == 0 {
+	    inode.i_block[slot] = self.alloc_blocks(1)?[0];
+	    inode.i_blocks += sectors;
+	    self.set_indirect_ptr(inode.i_block[slot], 0, 0);
+	}
+	let mut indirect = inode.i_block[slot];
+	for (i, &index) in path.iter().enumerate() {
+	    let mut next = self.indirect_ptr(indirect, index)?;
+	    if next == 0 {
+		next = match block {
+		    Some(block) if i + 1 == path.len() => block,
+		    _ => self.alloc_blocks(1)?[0]
+		};
+		inode.i_blocks += sectors;
+		self.set_indirect_ptr(indirect, index, next);
+		if i + 1 < path.len() {
+		    self.set_indirect_ptr(next, 0, 0);
+		}
+	    }
+	    indirect = next;
+	}
+	Ok(indirect)
+    }
+
+    // Maps the reserved blocks, in block order, as the contents of an inode
+    pub fn map_reserved(&mut self, inode : &mut Inode) -> Result<()> {
+	let mut extents = self.reserved.clone();
+	extents.sort();
+	let mut logical = 0;
+	for (start, len) in extents {
+	    for block in start .. start + len {
+		self.map_block(inode, logical, Some(block))?;
+		logical += 1;
+	    }
+	}
+	let size = logical as u64 * self.sb.block_size() as u64;
+	if size > self.max_file_size() {
+	    return Err(Ext2Error::Overflow(format!("{} reserved bytes is too large for a file", size)));
+	}
+	inode.set_size(size);
+	Ok(())
+    }
+
+    // Looks up the data block mapped at a logical block of the inode
+    pub fn block_at(&self, inode : &Inode, logical : u32) -> Result<u32> {
+	let (slot, path) = self.block_path(logical)?;
+	let mut block = inode.i_block[slot];
+	for &index in &path {
+	    if block == 0 {
+		break;
+	    }
+	    block = self.indirect_ptr(block, index)?;
+	}
+	Ok(block)
+    }
+
+    // The indirect blocks of an inode that are loaded in data, in tree order
+    pub fn indirect_blocks(&self, inode : &Inode) -> Result<Vec<u32>> {
+	let mut blocks = Vec::new();
+	let mut stack : Vec<(u32, u32)> = vec![(inode.i_block[EXT2_TIND_BLOCK], 3), (inode.i_block[EXT2_DIND_BLOCK], 2), (inode.i_block[EXT2_IND_BLOCK], 1)];
+	while let Some((block, depth)) = stack.pop() {
+	    if block == 0 || depth == 0 {
+		continue;
+	    }
+	    blocks.push(block);
+	    let ptrs = self.sb.block_size() / 4;
+	    for index in (0 .. ptrs).rev() {
+		stack.push((self.indirect_ptr(block, index)?, depth - 1));
+	    }
+	}
+	Ok(blocks)
+    }
+
+    // Reads the indirect blocks of an inode from the device into data, and
+    // returns the data blocks it maps, in logical order
+    pub fn load_blocks(&mut self, dev : &mut dyn BlockDevice, inode : &Inode) -> Result<Vec<u32>> {
+	let block_size = self.sb.block_size();
+	let mut buf = vec![0; block_size as usize];
+	let mut stack : Vec<(u32, u32)> = vec![(inode.i_block[EXT2_TIND_BLOCK], 3), (inode.i_block[EXT2_DIND_BLOCK], 2), (inode.i_block[EXT2_IND_BLOCK], 1)];
+	while let Some((block, depth)) = stack.pop() {
+	    if block == 0 || depth == 0 || self.data.contains_key(&block) {
+		continue;
+	    }
+	    dev.read_block(block as u64, &mut buf).map_err(|e| Ext2Error::io(format!("reading indirect block {}", block), e))?;
+	    self.data.insert(block, buf.clone());
+	    for ptr in buf.chunks(4).rev() {
+		stack.push((u32::from_le_bytes([ptr[0], ptr[1], ptr[2], ptr[3]]), depth - 1));
+	    }
+	}
+	let num_blocks = inode.size().div_ceil(block_size as u64) as u32;
+	(0 .. num_blocks).map(|i| self.block_at(inode, i)).collect()
+    }
+
+    pub fn set_inode_used(&mut self, ino : u32) {
+	let group = ((ino - 1) / self.sb.s_inodes_per_group) as usize;
+	let bmap = &mut self.inode_bmaps[group];
+	if !bmap.get((ino - 1) % self.sb.s_inodes_per_group) {
+	    bmap.set((ino - 1) % self.sb.s_inodes_per_group, true);
+	    self.bgds[group].bg_free_inodes_count -= 1;
+	    self.sb.s_free_inodes_count -= 1;
+	}
+    }
+
+    // Copies the host file contents into their blocks, a run at a time. The
+    // end of the last block is zero filled.
+    fn write_file_data(&self, dev : &mut dyn BlockDevice) -> Result<()> {
+	let block_size = self.sb.block_size() as u64;
+	const CHUNK_BLOCKS : u32 = 256;
+	let mut buf = Vec::new();
+	for (first, count, path, offset) in &self.file_data {
+	    let context = || format!("reading {}", path.display());
+	    let mut file = File::open(path).map_err(|e| Ext2Error::io(context(), e))?;
+	    file.seek(SeekFrom::Start(*offset)).map_err(|e| Ext2Error::io(context(), e))?;
+	    let mut block = *first;
+	    while block < first + count {
+		let blocks = std::cmp::min(CHUNK_BLOCKS, first + count - block);
+		buf.clear();
+		(&mut file).take(blocks as u64 * block_size).read_to_end(&mut buf).map_err(|e| Ext2Error::io(context(), e))?;
+		buf.resize((blocks as u64 * block_size) as usize, 0);
+		dev.write_at(block as u64 * block_size, &buf).map_err(|e| Ext2Error::io(format!("writing data block {}", block), e))?;
+		block += blocks;
+	    }
+	}
+	Ok(())
+    }
+
+    // Writes zeroes over whole blocks
+    fn zero_blocks(dev : &mut dyn BlockDevice, first : u32, count : u32, block_size : u32) -> Result<()> {
+	let zeroes = vec![0; block_size as usize];
+	for block in first .. first + count {
+	    dev.write_block(block as u64, &zeroes).map_err(|e| Ext2Error::io(format!("zeroing block {}", block), e))?;
+	}
+	Ok(())
+    }
+
+    // Everything the filesystem relies on is written out, so the device
+    // doesn't have to start out zeroed
+    pub fn write(&self, dev : &mut dyn BlockDevice) -> Result<()> {
+	let block_size = self.sb.block_size();
+	// The last block first, so the device is the full size even if
+	// nothing else ends up there
+	Ext2Image::zero_blocks(dev, self.sb.s_blocks_count - 1, 1, block_size)?;
+	for (i, bgd) in self.bgds.iter().enumerate() {
+	    let start = bgd.get_start(&self.sb) as u64 * block_size as u64;
+	    if i == 0 {
+		self.sb.write(dev, SUPERBLOCK_START)?;
+	    } else if self.sb.has_super(i as u32) {
+		// Each backup records which group it's in
+		let mut backup = self.sb.clone();
+		backup.s_block_group_nr = i as u16;
+		backup.write(dev, start)?;
+	    }
+	    if self.sb.has_super(i as u32) {
+		Ext2Image::zero_blocks(dev, bgd.get_start(&self.sb) + 1, self.sb.gdt_blocks(), block_size)?;
+		for (j, bgd) in self.bgds.iter().enumerate() {
+		    bgd.write(dev, start + block_size as u64 + (j as u64 * BGD_SIZE as u64))?;
+		}
+	    }
+	    self.block_bmaps[i].write(dev, bgd.bg_block_bitmap, block_size)?;
+	    self.inode_bmaps[i].write(dev, bgd.bg_inode_bitmap, block_size)?;
+	    Ext2Image::zero_blocks(dev, bgd.bg_inode_table, self.sb.itable_blocks(), block_size)?;
+	}
+
+	for (&ino, inode) in &self.inodes {
+	    inode.write(dev, self.inode_offset(ino), self.sb.inode_size())?;
+	}
+
+	for (ino, dir) in &self.dirs {
+	    let inode = &self.inodes[ino];
+	    let blocks = (0 .. dir.num_blocks()).map(|i| self.block_at(inode, i)).collect::<Result<Vec<u32>>>()?;
+	    dir.write(dev, &blocks)?;
+	}
+
+	for (&block, contents) in &self.data {
+	    let mut buf = contents.clone();
+	    buf.resize(block_size as usize, 0);
+	    dev.write_block(block as u64, &buf).map_err(|e| Ext2Error::io(format!("writing data block {}", block), e))?;
+	}
+
+	self.write_file_data(dev)?;
+
+	for (&offset, bytes) in &self.raw {
+	    dev.write_at(offset, bytes).map_err(|e| Ext2Error::io(format!("writing {} bytes at {}", bytes.len(), offset), e))?;
+	}
+	Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::Ext2Builder;
+    use crate::device::OffsetDevice;
+
+    fn build() -> Ext2Image {
+	let mut image = Ext2Builder::new(20000).reserved_sectors(100).build().unwrap();
+	image.write_file(ROOT_INO, "hello", Inode::new(false), b"hello world").unwrap();
+	image
+    }
+
+    #[test]
+    fn block_path_boundaries() {
+	// 1K blocks hold 256 pointers
+	let image = build();
+	let ind = EXT2_NDIR_BLOCKS as u32;
+	let dind = ind + 256;
+	let tind = dind + 256 * 256;
+	assert_eq!(image.block_path(0).unwrap(), (0, vec![]));
+	assert_eq!(image.block_path(ind - 1).unwrap(), (EXT2_NDIR_BLOCKS - 1, vec![]));
+	assert_eq!(image.block_path(ind).unwrap(), (EXT2_IND_BLOCK, vec![0]));
+	assert_eq!(image.block_path(dind - 1).unwrap(), (EXT2_IND_BLOCK, vec![255]));
+	assert_eq!(image.block_path(dind).unwrap(), (EXT2_DIND_BLOCK, vec![0, 0]));
+	assert_eq!(image.block_path(tind - 1).unwrap(), (EXT2_DIND_BLOCK, vec![255, 255]));
+	assert_eq!(image.block_path(tind).unwrap(), (EXT2_TIND_BLOCK, vec![0, 0, 0]));
+	assert_eq!(image.block_path(tind + 256 * 256 * 256 - 1).unwrap(), (EXT2_TIND_BLOCK, vec![255, 255, 255]));
+	assert!(matches!(image.block_path(tind + 256 * 256 * 256), Err(Ext2Error::Overflow(_))));
+    }
+
+    #[test]
+    fn round_trip_in_memory() {
+	let image = build();
+	let mut dev : Vec<u8> = Vec::new();
+	image.write(&mut dev).unwrap();
+	assert_eq!(dev.len() as u64, image.sb.s_blocks_count as u64 * image.sb.block_size() as u64);
+
+	let read = Ext2Image::read(&mut dev).unwrap();
+	assert_eq!(read.sb.s_blocks_count, image.sb.s_blocks_count);
+	assert_eq!(read.sb.s_free_blocks_count, image.sb.s_free_blocks_count);
+	assert_eq!(read.bgds.len(), image.bgds.len());
+	let ino = read.dirs[&ROOT_INO].find("hello").unwrap().inode;
+	let inode = read.read_inode(&mut dev, ino).unwrap();
+	assert_eq!(inode.i_size, 11);
+	assert_eq!(read.inodes[&5].i_size, image.inodes[&5].i_size);
+    }
+
+    #[test]
+    fn read_rejects_bad_geometry() {
+	let image = build();
+	let mut clean : Vec<u8> = Vec::new();
+	image.write(&mut clean).unwrap();
+	// Blocks per group, inodes per group, first data block, inode count
+	for offset in [32, 40, 20, 0] {
+	    let mut dev = clean.clone();
+	    dev[SUPERBLOCK_START as usize + offset .. SUPERBLOCK_START as usize + offset + 4].copy_from_slice(&[0; 4]);
+	    assert!(matches!(Ext2Image::read(&mut dev), Err(Ext2Error::Corrupt(_))));
+	}
+    }
+
+    #[test]
+    fn read_write_keeps_files() {
+	let mut image = build();
+	let sub = image.mkdir(ROOT_INO, "sub", Inode::new(true)).unwrap();
+	image.write_file(sub, "inner", Inode::new(false), &[7; 5000]).unwrap();
+	let mut dev : Vec<u8> = Vec::new();
+	image.write(&mut dev).unwrap();
+
+	let read = Ext2Image::read(&mut dev).unwrap();
+	read.write(&mut dev).unwrap();
+	let mut reread = Ext2Image::read(&mut dev).unwrap();
+	let ino = reread.dirs[&ROOT_INO].find("hello").unwrap().inode;
+	assert_eq!(reread.read_inode(&mut dev, ino).unwrap().i_size, 11);
+	let sub = reread.dirs[&ROOT_INO].find("sub").unwrap().inode;
+	let ino = reread.dirs[&sub].find("inner").unwrap().inode;
+	let inode = reread.read_inode(&mut dev, ino).unwrap();
+	assert_eq!(inode.i_size, 5000);
+	let blocks = reread.load_blocks(&mut dev, &inode).unwrap();
+	let mut buf = vec![0; reread.sb.block_size() as usize];
+	dev.read_block(blocks[0] as u64, &mut buf).unwrap();
+	assert!(buf.iter().all(|&b| b == 7));
+    }
+
+    #[test]
+    fn write_over_dirty_device() {
+	let image = build();
+	let size = image.sb.s_blocks_count as usize * image.sb.block_size() as usize;
+	let mut dev = OffsetDevice::new(vec![0xaa; 4096 + size], 4096);
+	image.write(&mut dev).unwrap();
+	let read = Ext2Image::read(&mut dev).unwrap();
+	for ino in [7, 11 + 1, read.sb.s_inodes_count] {
+	    let inode = read.read_inode(&mut dev, ino).unwrap();
+	    assert_eq!(inode.i_mode, 0);
+	    assert_eq!(inode.i_blocks, 0);
+	}
+    }
+}
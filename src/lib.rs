@@ -0,0 +1,13 @@
+pub mod error;
+pub mod ext2;
+pub mod device;
+pub mod image;
+pub mod builder;
+pub mod populate;
+pub mod planner;
+
+pub use error::Ext2Error;
+pub use image::Ext2Image;
+pub use planner::Plan;
+pub use builder::{Ext2Builder, Layout, ReservedPlacement, Rounding};
+pub use device::{BlockDevice, FileDevice, OffsetDevice};
@@ -1,224 +1,237 @@
 use std::env;
 use std::process;
+use std::fs;
 use std::fs::File;
-use std::io::Result as IOResult;
-use std::io::Seek;
-use std::convert::TryInto;
-
-mod ext2;
-use ext2::SECTOR_SIZE;
-use ext2::BLOCK_SIZE;
-
-fn main() {
-    let args: Vec<String> = env::args().collect();
-    if args.len() < 4 {
-	println!("Not enough arguments: {} <img file name> <Total size in sectors> <Number of reserved sectors>", &args[0]);
-	process::exit(1);
-    }
-    let num_sectors : u32 = match args[2].parse() {
-	Result::Err(_) => {
-	    println!("Invalid number of sectors: {}", &args[2]);
-	    process::exit(2);
-	},
-	Ok(i) => i
-    };
-    let num_sectors_res : u32 = match args[3].parse() {
-	Result::Err(_) => {
-	    println!("Invalid number of reserved sectors: {}", &args[3]);
-	    process::exit(3);
-	},
-	Ok(i) => i
-    };
-    let filename = &args[1];
+use std::str::FromStr;
+use std::path::PathBuf;
+
+use mkext2::Ext2Builder;
+use mkext2::{Layout, ReservedPlacement, Rounding};
+use mkext2::Ext2Error;
+use mkext2::Plan;
+use mkext2::ext2::features::{Feature, Features};
+use mkext2::FileDevice;
+use mkext2::ext2::SECTOR_SIZE;
+
+fn usage(prog : &str) -> ! {
+    println!("Usage: {} [-b <block size>] [-I <inode size>] [-O <feature>,^<feature>...] [-g <blocks per group>] [-N <number of inodes>] [-i <bytes per inode>] [--inodes-per-group <inodes>] [--root <dir>] [--sector-size <bytes>] [--reserved-at <sector> | --reserved-offset <bytes> | --reserved-at-end] [--reserved-first] [--rounding up|down|reject] [--reserve <sector>:<sectors>] [--reserve-file <file>] [--reserved-payload <file>] [--payload-padding <byte>] [--boot-code <file>] [--check-mbr-signature] [--sparse-super] [--dry-run [--json]] <img file name> <Total size in sectors> <Number of reserved sectors>", prog);
+    process::exit(1);
+}
 
-    let mut file = match File::create(filename) {
-	IOResult::Err(e) => {
-	    println!("IO Error when creating {}: {}", filename, e);
-	    process::exit(4);
+fn option_value<T : FromStr>(prog : &str, name : &str, value : Option<&String>) -> T {
+    match value.map(|v| v.parse()) {
+	Some(Ok(v)) => v,
+	Some(Err(_)) => {
+	    println!("Invalid value for {}: {}", name, value.unwrap());
+	    process::exit(1);
 	},
-	IOResult::Ok(f) => f
-    };
-
-    let res = file.set_len((num_sectors * SECTOR_SIZE).into());
-    if !res.is_ok() {
-	println!("IO Error when extending file: {}", res.err().unwrap());
-	process::exit(5);
-    }
-    let mut sb = ext2::superblock::Superblock::new(num_sectors, num_sectors_res);
-    if num_sectors_res * SECTOR_SIZE / BLOCK_SIZE >= sb.s_blocks_per_group + 1 {
-	println!("Too many reserved sectors!");
-	process::exit(16);
-    }
-    let mut bgd_reserved = ext2::bgd::BGD::new(&sb, 0);
-    let num_groups = (sb.s_blocks_count + sb.s_blocks_per_group - 1) / sb.s_blocks_per_group;
-    let mut bgds : Vec<ext2::bgd::BGD> = Vec::new();
-    for i in 1 .. num_groups {
-	bgds.push(ext2::bgd::BGD::new(&sb, i));
+	None => usage(prog)
     }
+}
 
-    let used_blk_id = bgd_reserved.bg_inode_table + (sb.s_inodes_per_group * ext2::inode::INODE_SIZE / BLOCK_SIZE);
-    let mut block_bmap_reserved = ext2::bitmap::Bitmap::new(sb.s_blocks_per_group);
-    for i in 0 .. used_blk_id {
-	block_bmap_reserved.set(i, true);
-	sb.s_free_blocks_count -= 1;
-	bgd_reserved.bg_free_blocks_count -= 1;
-    }
+// Reserved regions are written as <first sector>:<number of sectors>
+fn parse_region(s : &str) -> Option<(u64, u64)> {
+    let (start, sectors) = s.split_once(':')?;
+    Some((start.trim().parse().ok()?, sectors.trim().parse().ok()?))
+}
 
-    if num_sectors_res > used_blk_id {
-	for i in used_blk_id .. (num_sectors_res * SECTOR_SIZE / BLOCK_SIZE) - 1 {
-	    block_bmap_reserved.set(i, true);
-	    sb.s_free_blocks_count -= 1;
-	    bgd_reserved.bg_free_blocks_count -= 1;
+// A region file has one region per line. Blank lines and lines starting
+// with # are skipped.
+fn read_regions(filename : &str) -> Vec<(u64, u64)> {
+    let contents = match fs::read_to_string(filename) {
+	Ok(contents) => contents,
+	Err(e) => {
+	    let e = Ext2Error::io(format!("reading {}", filename), e);
+	    println!("{}", e);
+	    process::exit(exit_code(&e));
+	}
+    };
+    let mut regions = Vec::new();
+    for (i, line) in contents.lines().enumerate() {
+	let line = line.trim();
+	if line.is_empty() || line.starts_with('#') {
+	    continue;
+	}
+	match parse_region(line) {
+	    Some(region) => regions.push(region),
+	    None => {
+		println!("Invalid reserved region on line {} of {}: {}", i + 1, filename, line);
+		process::exit(1);
+	    }
 	}
     }
+    regions
+}
 
-    let mut inode_bmap_reserved = ext2::bitmap::Bitmap::new(sb.s_inodes_per_group);
-    for i in 0 .. sb.s_first_ino {
-	inode_bmap_reserved.set(i, true);
-	sb.s_free_inodes_count -= 1;
-	bgd_reserved.bg_free_inodes_count -= 1;
+// Exit codes 1 to 3 are for bad arguments. The rest are stable per error kind.
+fn exit_code(e : &Ext2Error) -> i32 {
+    match e {
+	Ext2Error::Io { .. } => 4,
+	Ext2Error::InvalidGeometry(_) => 5,
+	Ext2Error::TooManyReservedSectors { .. } => 6,
+	Ext2Error::Overflow(_) => 7,
+	Ext2Error::OutOfSpace(_) => 8,
+	Ext2Error::InvalidInput(_) => 9,
+	Ext2Error::Corrupt(_) => 10
     }
+}
 
-    let mut block_bmaps : Vec<ext2::bitmap::Bitmap> = Vec::new();
-    let mut inode_bmaps : Vec<ext2::bitmap::Bitmap> = Vec::new();
-    for i in 0 .. num_groups - 1 {
-	let mut bgd = &mut bgds[i as usize];
-	let mut block_bmap = ext2::bitmap::Bitmap::new(sb.s_blocks_per_group);
-	for j in 0 .. bgd.bg_inode_table - bgd.get_start(&sb) + (sb.s_inodes_per_group * ext2::inode::INODE_SIZE / BLOCK_SIZE) {
-	    block_bmap.set(j, true);
-	    sb.s_free_blocks_count -= 1;
-	    bgd.bg_free_blocks_count -= 1;
-	}
-	
-	let inode_bmap = ext2::bitmap::Bitmap::new(sb.s_inodes_per_group);
-	block_bmaps.push(block_bmap);
-	inode_bmaps.push(inode_bmap);
+// Prints where everything would go, without writing the image
+fn plan(builder : &Ext2Builder, sector_size : u32, json : bool) -> Result<(), Ext2Error> {
+    let plan = Plan::new(&builder.build()?, sector_size);
+    if json {
+	println!("{}", plan.to_json());
+    } else {
+	print!("{}", plan);
     }
+    Ok(())
+}
 
-    let mut inodes : Vec<ext2::inode::Inode> = Vec::new();
-//    inodes.push(ext2::inode::Inode::new(false)); // empty inode
-    inodes.push(Default::default()); // Bad Blocks inode
-    let mut root_inode = ext2::inode::Inode::new(true);
-    root_inode.i_block[0] = block_bmap_reserved.alloc(&mut bgd_reserved, &mut sb).unwrap();
-    root_inode.i_blocks += BLOCK_SIZE / SECTOR_SIZE;
-
-    let root_dir : ext2::directory::Directory = ext2::directory::Directory::new(2, 2);
-    root_inode.i_size = 1024;//root_dir.len();
-
-    inodes.push(root_inode); // Root Directory inode
-    inodes.push(Default::default()); // ACL index inode
-    inodes.push(Default::default()); // ACL data inode
-    inodes.push(ext2::inode::Inode::new(false)); // boot loader inode
-    inodes.push(ext2::inode::Inode::new(true)); // undelete directory inode
-    bgd_reserved.bg_used_dirs_count += 2;
-
-
-    let res = file.seek(std::io::SeekFrom::Start(ext2::superblock::SUPERBLOCK_START));
-    if !res.is_ok() {
-	println!("IO Error when seeking to superblock: {}", res.err().unwrap());
-	process::exit(20);
-    }
-    let res = sb.write(&file);
-    if !res.is_ok() {
-	println!("IO Error when writing superblock: {}", res.err().unwrap());
-	process::exit(6);
-    }
-    let res = bgd_reserved.write(&file);
-    if !res.is_ok() {
-	println!("IO Error when writing reserved bgd: {}", res.err().unwrap());
-	process::exit(7);
-    }
-    for i in 0 .. (num_groups - 1) {
-	let bgd = &bgds[i as usize];
-	let res = bgd.write(&file);
-	if !res.is_ok() {
-	    println!("IO Error when writing bgd {}: {}", i + 1, res.err().unwrap());
-	    process::exit(15);
-	}
+fn create(builder : &Ext2Builder, filename : &str, num_sectors : u64, sector_size : u32) -> Result<(), Ext2Error> {
+    let image = builder.build()?;
+    let file = File::create(filename).map_err(|e| Ext2Error::io(format!("creating {}", filename), e))?;
+    file.set_len(num_sectors * sector_size as u64).map_err(|e| Ext2Error::io(format!("extending {}", filename), e))?;
+    let mut dev = FileDevice::new(file);
+    image.write(&mut dev)?;
+    for &(start, sectors) in &image.protected {
+	println!("Reserved sectors {} to {}", start, start + sectors - 1);
     }
-    let res = file.seek(std::io::SeekFrom::Current((BLOCK_SIZE - (num_groups * ext2::bgd::BGD_SIZE)).try_into().unwrap()));
-    if !res.is_ok() {
-	println!("IO Error when seeking to reserved block bitmap: {}", res.err().unwrap());
-	process::exit(9);
-    }
-
-    let res = block_bmap_reserved.write(&file);
-    if !res.is_ok() {
-	println!("IO Error when writing reserved block bitmap: {}", res.err().unwrap());
-	process::exit(7);
+    // Where the payload ended up, for whatever loads it
+    for &(start, sectors) in &image.payload {
+	println!("Payload at LBA {}, {} sectors", start, sectors);
     }
+    Ok(())
+}
 
-    let res = inode_bmap_reserved.write(&file);
-    if !res.is_ok() {
-	println!("IO Error when writing reserved inode bitmap: {}", res.err().unwrap());
-	process::exit(7);
+fn main() {
+    let argv: Vec<String> = env::args().collect();
+    let prog = &argv[0];
+    let mut args : Vec<String> = vec![prog.clone()];
+    let mut builder = Ext2Builder::new(0);
+    let mut features = Features::default();
+    let mut dry_run = false;
+    let mut json = false;
+    let mut sector_size = SECTOR_SIZE;
+    let mut reserved_offset : Option<u64> = None;
+    let mut iter = argv.iter().skip(1);
+    while let Some(arg) = iter.next() {
+	match arg.as_str() {
+	    "-b" => builder = builder.block_size(option_value(prog, arg, iter.next())),
+	    "-I" => builder = builder.inode_size(option_value(prog, arg, iter.next())),
+	    "-g" => builder = builder.blocks_per_group(option_value(prog, arg, iter.next())),
+	    "-N" => builder = builder.inodes_count(option_value(prog, arg, iter.next())),
+	    "-i" => builder = builder.inode_ratio(option_value(prog, arg, iter.next())),
+	    "--inodes-per-group" => builder = builder.inodes_per_group(option_value(prog, arg, iter.next())),
+	    "--root" => builder = builder.root(&option_value::<PathBuf>(prog, arg, iter.next())),
+	    "--reserved-at" => builder = builder.reserved_placement(ReservedPlacement::Sector(option_value(prog, arg, iter.next()))),
+	    "--reserved-offset" => reserved_offset = Some(option_value(prog, arg, iter.next())),
+	    "--sector-size" => {
+		sector_size = option_value(prog, arg, iter.next());
+		builder = builder.sector_size(sector_size);
+	    },
+	    "--reserved-at-end" => builder = builder.reserved_placement(ReservedPlacement::End),
+	    "--dry-run" => dry_run = true,
+	    "--json" => json = true,
+	    "--reserved-first" => builder = builder.layout(Layout::ReservedFirst),
+	    "--rounding" => {
+		let value : String = option_value(prog, arg, iter.next());
+		let rounding = match value.as_str() {
+		    "up" => Rounding::Up,
+		    "down" => Rounding::Down,
+		    "reject" => Rounding::Reject,
+		    _ => {
+			println!("Invalid value for {}: {}", arg, value);
+			process::exit(1);
+		    }
+		};
+		builder = builder.rounding(rounding);
+	    },
+	    "--reserve" => {
+		let value : String = option_value(prog, arg, iter.next());
+		match parse_region(&value) {
+		    Some((start, sectors)) => builder = builder.reserved_region(start, sectors),
+		    None => {
+			println!("Invalid reserved region: {}", value);
+			process::exit(1);
+		    }
+		}
+	    },
+	    "--reserved-payload" => builder = builder.reserved_payload(&option_value::<PathBuf>(prog, arg, iter.next())),
+	    "--payload-padding" => {
+		let value : String = option_value(prog, arg, iter.next());
+		let padding = match value.strip_prefix("0x") {
+		    Some(hex) => u8::from_str_radix(hex, 16),
+		    None => value.parse()
+		};
+		match padding {
+		    Ok(padding) => builder = builder.payload_padding(padding),
+		    Err(_) => {
+			println!("Invalid value for {}: {}", arg, value);
+			process::exit(1);
+		    }
+		}
+	    },
+	    "--boot-code" => builder = builder.boot_code(&option_value::<PathBuf>(prog, arg, iter.next())),
+	    "--check-mbr-signature" => builder = builder.check_boot_signature(true),
+	    "-O" => {
+		let value : String = option_value(prog, arg, iter.next());
+		if let Err(e) = features.edit(&value) {
+		    println!("{}", e);
+		    process::exit(exit_code(&e));
+		}
+	    },
+	    "--sparse-super" => features.insert(Feature::SparseSuper),
+	    "--reserve-file" => {
+		for (start, sectors) in read_regions(&option_value::<String>(prog, arg, iter.next())) {
+		    builder = builder.reserved_region(start, sectors);
+		}
+	    },
+	    _ if arg.starts_with('-') => {
+		println!("Unknown option: {}", arg);
+		usage(prog);
+	    },
+	    _ => args.push(arg.clone())
+	}
     }
-    for inode in &inodes {
-	let res = inode.write(&file);
-	if !res.is_ok() {
-	    println!("IO Error when writing inode table: {}", res.err().unwrap());
-	    process::exit(8);
+    if let Some(offset) = reserved_offset {
+	if sector_size == 0 || !offset.is_multiple_of(sector_size as u64) {
+	    println!("Reserved offset {} is not a multiple of {} bytes", offset, sector_size);
+	    process::exit(1);
 	}
+	builder = builder.reserved_placement(ReservedPlacement::Sector(offset / sector_size as u64));
     }
-    let res = file.seek(std::io::SeekFrom::Current((((sb.s_inodes_per_group) - inodes.len() as u32) * ext2::inode::INODE_SIZE).try_into().unwrap()));
-    if !res.is_ok() {
-	println!("IO Error when seeking past inode table: {}", res.err().unwrap());
-	process::exit(9);
+    if args.len() < 4 {
+	println!("Not enough arguments: {} <img file name> <Total size in sectors> <Number of reserved sectors>", &args[0]);
+	usage(prog);
     }
-    for i in 0 .. (num_groups - 1) {
-	let bgd = &bgds[i as usize];
-	let block_bmap = &block_bmaps[i as usize];
-	let inode_bmap = &inode_bmaps[i as usize];
-
-	let res = file.seek(std::io::SeekFrom::Start(bgd.get_start(&sb) as u64 * BLOCK_SIZE as u64));
-	if !res.is_ok() {
-	    println!("IO Error when seeking to bgd #{}: {}", i, res.err().unwrap());
-	    process::exit(9);
-	}
-	let res = sb.write(&file);
-	if !res.is_ok() {
-	    println!("IO Error when writing superblock backup {}: {}", i, res.err().unwrap());
-	    process::exit(6);
-	}
-	let res = bgd_reserved.write(&file);
-	if !res.is_ok() {
-	    println!("IO Error when writing reserved bgd backup {}: {}", i, res.err().unwrap());
-	    process::exit(7);
-	}
-	for j in 0 .. (num_groups - 1) {
-	    let bgd = &bgds[j as usize];
-	    let res = bgd.write(&file);
-	    if !res.is_ok() {
-		println!("IO Error when writing backup bgd {} {}: {}", i, j + 1, res.err().unwrap());
-		process::exit(15);
-	    }
-	}
-
-	let res = file.seek(std::io::SeekFrom::Current((BLOCK_SIZE - (num_groups * ext2::bgd::BGD_SIZE)).try_into().unwrap()));
-	if !res.is_ok() {
-	    println!("IO Error when seeking to reserved block bitmap: {}", res.err().unwrap());
-	    process::exit(9);
-	}
-	let res = block_bmap.write(&file);
-	if !res.is_ok() {
-	    println!("IO Error when writing bitmap {}: {}", i, res.err().unwrap());
-	    process::exit(10);
-	}
-	let res = inode_bmap.write(&file);
-	if !res.is_ok() {
-	    println!("IO Error when writing inode bitmap {}: {}", i, res.err().unwrap());
-	    process::exit(11);
-	}
+    if args.len() > 4 {
+	println!("Too many arguments: {}", args[4 ..].join(" "));
+	usage(prog);
     }
+    let num_sectors : u64 = match args[2].parse() {
+	Result::Err(_) => {
+	    println!("Invalid number of sectors: {}", &args[2]);
+	    process::exit(2);
+	},
+	Ok(i) => i
+    };
+    let num_sectors_res : u64 = match args[3].parse() {
+	Result::Err(_) => {
+	    println!("Invalid number of reserved sectors: {}", &args[3]);
+	    process::exit(3);
+	},
+	Ok(i) => i
+    };
+    let filename = &args[1];
 
-    let res = file.seek(std::io::SeekFrom::Start((BLOCK_SIZE * inodes[1].i_block[0]) as u64));
-    if !res.is_ok() {
-	println!("IO Error when seeking to root directory: {}", res.err().unwrap());
-	process::exit(18);
-    }
-    let res = root_dir.write(&file);
-    if !res.is_ok() {
-	println!("IO Error when writing to root directory: {}", res.err().unwrap());
-	process::exit(19);
+    let builder = builder.num_sectors(num_sectors).reserved_sectors(num_sectors_res).features(features);
+    let res = if dry_run {
+	plan(&builder, sector_size, json)
+    } else {
+	create(&builder, filename, num_sectors, sector_size)
+    };
+    if let Err(e) = res {
+	println!("{}", e);
+	process::exit(exit_code(&e));
     }
 }
@@ -0,0 +1,175 @@
+use std::fmt;
+
+use crate::ext2::superblock::{SUPERBLOCK_START, SUPERBLOCK_SIZE};
+use crate::image::Ext2Image;
+use crate::image::ROOT_INO;
+
+const BOOT_LOADER_INO : u32 = 5;
+
+// Where one structure sits on the device, in bytes
+#[derive(Debug)]
+pub struct Region {
+    pub structure : &'static str,
+    pub group : Option<u32>,
+    pub offset : u64,
+    pub length : u64
+}
+
+// The on-disk layout of an image, worked out without writing anything
+#[derive(Debug)]
+pub struct Plan {
+    pub block_size : u32,
+    pub sector_size : u32,
+    pub regions : Vec<Region>
+}
+
+impl Plan {
+    pub fn new(image : &Ext2Image, sector_size : u32) -> Self {
+	let sb = &image.sb;
+	let block_size = sb.block_size();
+	let bs = block_size as u64;
+	let ss = sector_size as u64;
+	let mut regions = Vec::new();
+	let mut add = |structure, group, first_block : u64, blocks : u64| {
+	    regions.push(Region {
+		structure,
+		group,
+		offset: first_block * bs,
+		length: blocks * bs
+	    });
+	};
+
+	for (i, bgd) in image.bgds.iter().enumerate() {
+	    let group = Some(i as u32);
+	    let start = bgd.get_start(sb) as u64;
+	    if sb.has_super(i as u32) {
+		if i > 0 {
+		    add("backup superblock", group, start, 1);
+		}
+		add("group descriptors", group, start + 1, bgd.descriptors_end(sb) as u64 - start - 1);
+	    }
+	    add("block bitmap", group, bgd.bg_block_bitmap as u64, 1);
+	    add("inode bitmap", group, bgd.bg_inode_bitmap as u64, 1);
+	    add("inode table", group, bgd.bg_inode_table as u64, sb.itable_blocks() as u64);
+
+	    // Runs of clear bits in the block bitmap
+	    let bmap = &image.block_bmaps[i];
+	    let mut free_start = None;
+	    for bit in 0 ..= bgd.num_blocks(sb) {
+		let free = bit < bgd.num_blocks(sb) && !bmap.get(bit);
+		match (free, free_start) {
+		    (true, None) => free_start = Some(bit),
+		    (false, Some(first)) => {
+			add("free", group, start + first as u64, (bit - first) as u64);
+			free_start = None;
+		    },
+		    _ => ()
+		}
+	    }
+	}
+
+	if let (Some(dir), Some(inode)) = (image.dirs.get(&ROOT_INO), image.inodes.get(&ROOT_INO)) {
+	    for logical in 0 .. dir.num_blocks() {
+		if let Ok(block) = image.block_at(inode, logical) {
+		    add("root directory", None, block as u64, 1);
+		}
+	    }
+	}
+
+	// The reserved blocks are the contents of the boot loader inode, which
+	// also needs indirect blocks once there are more than a few of them
+	if let Some(inode) = image.inodes.get(&BOOT_LOADER_INO) {
+	    let num_blocks = inode.size().div_ceil(bs) as u32;
+	    let data = (0 .. num_blocks).map(|logical| image.block_at(inode, logical)).collect::<Result<Vec<u32>, _>>();
+	    let indirect = image.indirect_blocks(inode);
+	    for (structure, blocks) in [("boot loader inode", data), ("boot loader indirect", indirect)] {
+		let mut runs : Vec<(u32, u32)> = Vec::new();
+		for block in blocks.unwrap_or_default() {
+		    match runs.last_mut() {
+			Some((first, len)) if *first + *len == block => *len += 1,
+			_ => runs.push((block, 1))
+		    }
+		}
+		for (first, len) in runs {
+		    add(structure, None, first as u64, len as u64);
+		}
+	    }
+	}
+
+	// Boot code is the only raw write at the very start that isn't payload
+	let boot_code = image.raw.get(&0).filter(|_| image.payload.first().map(|p| p.0) != Some(0));
+	if let Some(code) = boot_code {
+	    regions.push(Region {
+		structure: "boot code",
+		group: None,
+		offset: 0,
+		length: code.len() as u64
+	    });
+	}
+	regions.push(Region {
+	    structure: "superblock",
+	    group: Some(0),
+	    offset: SUPERBLOCK_START,
+	    length: SUPERBLOCK_SIZE
+	});
+	for &(first, sectors) in &image.protected {
+	    regions.push(Region {
+		structure: "reserved",
+		group: None,
+		offset: first * ss,
+		length: sectors * ss
+	    });
+	}
+	for &(first, sectors) in &image.payload {
+	    regions.push(Region {
+		structure: "payload",
+		group: None,
+		offset: first * ss,
+		length: sectors * ss
+	    });
+	}
+
+	regions.sort_by_key(|r| (r.offset, r.length));
+	Plan {
+	    block_size,
+	    sector_size,
+	    regions
+	}
+    }
+
+    pub fn to_json(&self) -> String {
+	let mut out = format!("{{\"block_size\": {}, \"sector_size\": {}, \"regions\": [", self.block_size, self.sector_size);
+	for (i, r) in self.regions.iter().enumerate() {
+	    if i > 0 {
+		out.push(',');
+	    }
+	    let group = match r.group {
+		Some(group) => group.to_string(),
+		None => String::from("null")
+	    };
+	    out.push_str(&format!("\n  {{\"structure\": \"{}\", \"group\": {}, \"offset\": {}, \"length\": {}, \"first_block\": {}, \"blocks\": {}, \"first_sector\": {}, \"sectors\": {}}}",
+				  r.structure, group, r.offset, r.length,
+				  r.offset / self.block_size as u64, r.length.div_ceil(self.block_size as u64),
+				  r.offset / self.sector_size as u64, r.length.div_ceil(self.sector_size as u64)));
+	}
+	out.push_str("\n]}");
+	out
+    }
+}
+
+impl fmt::Display for Plan {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+	writeln!(f, "{:>10} {:>10} {:>12} {:>12} {:>6}  Structure", "Block", "Blocks", "Sector", "Sectors", "Group")?;
+	for r in &self.regions {
+	    let group = match r.group {
+		Some(group) => group.to_string(),
+		None => String::from("-")
+	    };
+	    writeln!(f, "{:>10} {:>10} {:>12} {:>12} {:>6}  {}",
+		     r.offset / self.block_size as u64, r.length.div_ceil(self.block_size as u64),
+		     r.offset / self.sector_size as u64, r.length.div_ceil(self.sector_size as u64),
+		     group, r.structure)?;
+	}
+	Ok(())
+    }
+}
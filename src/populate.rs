@@ -0,0 +1,108 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+use std::collections::HashMap;
+use std::os::unix::fs::{MetadataExt, FileTypeExt};
+use std::os::unix::ffi::OsStrExt;
+
+use crate::ext2::inode::Inode;
+use crate::image::Ext2Image;
+use crate::image::ROOT_INO;
+use crate::error::{Ext2Error, Result};
+
+// Copies a host directory tree into the image, starting at the root
+// directory. Hard links within the tree are preserved.
+pub fn populate(image : &mut Ext2Image, root : &Path) -> Result<()> {
+    let meta = with_path(root, fs::metadata(root))?;
+    if !meta.is_dir() {
+	return Err(Ext2Error::InvalidInput(format!("{} is not a directory", root.display())));
+    }
+    if let Some(inode) = image.inodes.get_mut(&ROOT_INO) {
+	let links = inode.i_links_count;
+	copy_attributes(inode, &meta);
+	inode.i_links_count = links;
+    }
+    let mut hard_links = HashMap::new();
+    add_directory(image, ROOT_INO, root, &mut hard_links)
+}
+
+fn add_directory(image : &mut Ext2Image, dir_ino : u32, path : &Path, hard_links : &mut HashMap<(u64, u64), u32>) -> Result<()> {
+    let mut entries = with_path(path, fs::read_dir(path).and_then(|dir| dir.collect::<io::Result<Vec<_>>>()))?;
+    // Sorted so the same tree always produces the same image
+    entries.sort_by_key(|e| e.file_name());
+    for entry in entries {
+	let child = entry.path();
+	let name = match entry.file_name().into_string() {
+	    Ok(name) => name,
+	    Err(_) => return Err(Ext2Error::InvalidInput(format!("{} is not a UTF-8 name", child.display())))
+	};
+	let meta = with_path(&child, entry.metadata())?;
+	let file_type = meta.file_type();
+
+	if !file_type.is_dir() && meta.nlink() > 1 {
+	    if let Some(&ino) = hard_links.get(&(meta.dev(), meta.ino())) {
+		image.link(dir_ino, &name, ino)?;
+		continue;
+	    }
+	}
+
+	let mut inode : Inode = Default::default();
+	copy_attributes(&mut inode, &meta);
+	let ino = if file_type.is_dir() {
+	    let ino = image.mkdir(dir_ino, &name, inode)?;
+	    add_directory(image, ino, &child, hard_links)?;
+	    ino
+	} else if file_type.is_file() {
+	    image.copy_file(dir_ino, &name, inode, &child, meta.len())?
+	} else if file_type.is_symlink() {
+	    let target = with_path(&child, fs::read_link(&child))?;
+	    image.symlink(dir_ino, &name, inode, target.as_os_str().as_bytes())?
+	} else if file_type.is_char_device() || file_type.is_block_device() {
+	    let rdev = meta.rdev();
+	    let major = ((rdev >> 8) & 0xfff) | ((rdev >> 32) & !0xfff);
+	    let minor = (rdev & 0xff) | ((rdev >> 12) & !0xff);
+	    image.mknod(dir_ino, &name, inode, major as u32, minor as u32)?
+	} else {
+	    image.mknod(dir_ino, &name, inode, 0, 0)?
+	};
+
+	if !file_type.is_dir() && meta.nlink() > 1 {
+	    hard_links.insert((meta.dev(), meta.ino()), ino);
+	}
+    }
+    Ok(())
+}
+
+fn copy_attributes(inode : &mut Inode, meta : &fs::Metadata) {
+    inode.i_mode = meta.mode() as u16;
+    inode.i_uid = meta.uid() as u16;
+    inode.l_i_uid_high = (meta.uid() >> 16) as u16;
+    inode.i_gid = meta.gid() as u16;
+    inode.l_i_gid_high = (meta.gid() >> 16) as u16;
+    inode.i_atime = timestamp(meta.atime());
+    inode.i_ctime = timestamp(meta.ctime());
+    inode.i_mtime = timestamp(meta.mtime());
+    // Only written out with large inodes
+    inode.i_atime_extra = timestamp_extra(meta.atime(), meta.atime_nsec());
+    inode.i_ctime_extra = timestamp_extra(meta.ctime(), meta.ctime_nsec());
+    inode.i_mtime_extra = timestamp_extra(meta.mtime(), meta.mtime_nsec());
+    if let Some(created) = meta.created().ok().and_then(|t| t.duration_since(UNIX_EPOCH).ok()) {
+	inode.i_crtime = timestamp(created.as_secs() as i64);
+	inode.i_crtime_extra = timestamp_extra(created.as_secs() as i64, created.subsec_nanos() as i64);
+    }
+}
+
+fn timestamp(secs : i64) -> u32 {
+    secs.clamp(0, u32::MAX as i64) as u32
+}
+
+// The low 32 bits of a time are signed, so times past 2038 need epoch 1
+fn timestamp_extra(secs : i64, nsec : i64) -> u32 {
+    let epoch = ((timestamp(secs) as i64 - timestamp(secs) as i32 as i64) >> 32) as u32;
+    ((nsec as u32) << 2) | epoch
+}
+
+fn with_path<T>(path : &Path, res : io::Result<T>) -> Result<T> {
+    res.map_err(|e| Ext2Error::io(format!("reading {}", path.display()), e))
+}